@@ -1,19 +1,24 @@
 // --- Imports ---
-use clap::{Parser, Subcommand};
-use serde::Deserialize;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
 // serde_json is used via its full path like serde_json::from_str, so top-level import removed by clippy
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::num::NonZeroUsize;
 use std::io::{self, BufRead, Write}; 
 use std::error::Error;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::time::{Duration, Instant};
 use tempfile::tempdir;
 use sha2::{Digest, Sha256};
 use reqwest::blocking::Client;
+use notify::Watcher;
 
 // --- Constants ---
 const SPHEREHUB_REGISTRY_URL: &str = "https://raw.githubusercontent.com/Nakadra/sphere-hub-registry/main/registry/";
+/// Directory inside the sphere's workspace that serves as its HOME.
+const SPHERE_HOME_DIR: &str = "home";
 
 
 // --- CLI Definition using clap ---
@@ -35,6 +40,98 @@ enum Commands {
         /// The .sphere file to execute
         #[arg(required = true)]
         file_path: PathBuf,
+        /// Run the entrypoint directly on the host without namespace isolation
+        #[arg(long)]
+        no_sandbox: bool,
+        /// Wall-clock limit for the run (e.g. 90s, 5m, 1h); overrides the manifest's `timeout`
+        #[arg(long, value_parser = parse_duration)]
+        timeout: Option<Duration>,
+        /// How to show the entrypoint's stdout and stderr
+        #[arg(long, value_enum, default_value_t = OutputMode::Stream)]
+        output_mode: OutputMode,
+        /// Don't connect this terminal's stdin to the entrypoint
+        #[arg(long)]
+        no_stdin: bool,
+        /// Set an environment variable for the entrypoint; overrides the manifest's `[env]`
+        #[arg(long = "env", value_name = "KEY=VAL", value_parser = parse_env_assignment)]
+        env: Vec<(String, String)>,
+        /// Directory that files matching the manifest's `outputs` are copied into [default: .]
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+        /// Keep the sandbox workspace after the run and print its path
+        #[arg(long)]
+        keep_sandbox: bool,
+        /// Use this directory (created if missing, must be empty) as the workspace; it is never removed
+        #[arg(long, value_name = "DIR")]
+        workdir: Option<PathBuf>,
+        /// Delete whatever a previous run left in `--workdir` instead of refusing to start
+        #[arg(long, requires = "workdir")]
+        workdir_reuse: bool,
+        /// Resolve dependencies from the local cache only, never touching the network (or set SPHERE_OFFLINE=1)
+        #[arg(long)]
+        offline: bool,
+        /// Fail if resolved dependencies differ in any way from sphere.lock
+        #[arg(long)]
+        locked: bool,
+        /// How many independent [[steps]] may run at the same time
+        #[arg(long, value_name = "N", default_value = "1")]
+        jobs: NonZeroUsize,
+        /// Run only this step of a [[steps]] pipeline, plus the steps it needs
+        #[arg(long, value_name = "NAME")]
+        step: Option<String>,
+        /// Write a JSON report of the run to this file
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+        /// Print status messages as text, or only a JSON report of the run on stdout
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text)]
+        format: ReportFormat,
+        /// Arguments passed to the entrypoint as "$@" (after `--`)
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Run a .sphere file, and run it again whenever it or the files it uses change
+    Watch {
+        /// How long changes must settle before the next run starts (e.g. 300ms, 2s)
+        #[arg(long, value_parser = parse_duration, default_value = "300ms")]
+        debounce: Duration,
+        /// The .sphere file to execute
+        #[arg(required = true)]
+        file_path: PathBuf,
+        /// Options for `sphere run`, then `--` and the entrypoint's arguments
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "RUN_ARGS")]
+        run_args: Vec<String>,
+    },
+    /// Start a service Sphere in the background
+    Up {
+        /// Name to manage the service by [default: the file name without its extension]
+        #[arg(long)]
+        name: Option<String>,
+        /// The .sphere file declaring `kind = "service"`
+        #[arg(required = true)]
+        file_path: PathBuf,
+        /// Options for `sphere run`, then `--` and the entrypoint's arguments
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "RUN_ARGS")]
+        run_args: Vec<String>,
+    },
+    /// Stop a service started with `sphere up`
+    Down {
+        /// The service's name, as shown by `sphere ps`
+        name: String,
+    },
+    /// List services started with `sphere up`
+    Ps,
+    /// Show a service's output
+    Logs {
+        /// The service's name, as shown by `sphere ps`
+        name: String,
+        /// Keep printing new output until the service stops
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Keeps one service running; started by `sphere up`
+    #[command(name = "__supervise", hide = true)]
+    Supervise {
+        name: String,
     },
     /// Manage the local Sphere cache
     Cache {
@@ -49,6 +146,24 @@ enum Commands {
     },
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputMode {
+    /// Pass both streams straight through to the terminal as they are written
+    Stream,
+    /// Interleave both streams on stdout, line by line, prefixed with their origin
+    Prefixed,
+    /// Buffer both streams and print them in framed sections once the run ends
+    Framed,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReportFormat {
+    /// Human-readable status messages
+    Text,
+    /// Nothing but the run report, with the Sphere's output captured into it
+    Json,
+}
+
 #[derive(Subcommand, Debug)]
 enum CacheAction {
     /// List all Spheres in the local cache index
@@ -77,13 +192,187 @@ enum CacheAction {
 #[derive(Deserialize, Debug)]
 struct SphereProcess {
     id: Option<String>,
-    entrypoint: String,
-    dependencies: Option<HashMap<String, String>>,
+    /// Required unless the sphere is a pipeline of `[[steps]]`.
+    entrypoint: Option<Entrypoint>,
+    /// Program that runs a script entrypoint: a name looked up in PATH, or a path. Defaults to sh.
+    interpreter: Option<String>,
+    dependencies: Option<HashMap<String, DependencySpec>>,
+    permissions: Option<SpherePermissions>,
+    #[serde(default)]
+    network: NetworkPolicy,
+    limits: Option<SphereLimits>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    timeout: Option<Duration>,
+    seccomp: Option<String>,
+    /// Exit codes that count as a successful run. Defaults to `[0]`.
+    success_codes: Option<Vec<i32>>,
+    env: Option<BTreeMap<String, String>>,
+    /// Host variables copied into the sphere's environment. A trailing `*` matches a prefix.
+    env_passthrough: Option<Vec<String>>,
+    /// Glob patterns, relative to the workspace, of files copied out after a successful run.
+    outputs: Option<Vec<String>>,
+    inputs: Option<Vec<SphereInput>>,
+    steps: Option<Vec<SphereStep>>,
+    /// Runs in the workspace before the entrypoint or the first step.
+    setup: Option<Entrypoint>,
+    /// Runs last, whether the run succeeded, failed or timed out.
+    teardown: Option<Entrypoint>,
+    /// Runs before `teardown` when the run did not succeed.
+    on_failure: Option<Entrypoint>,
+    /// How many more times a failed run is attempted, each time in a fresh workspace and sandbox.
+    retries: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    retry_delay: Option<Duration>,
+    #[serde(default)]
+    retry_backoff: RetryBackoff,
+    /// Exit codes worth another attempt. Defaults to every failing exit code.
+    retry_on: Option<Vec<i32>>,
+    #[serde(default)]
+    kind: SphereKind,
+    /// For services: whether their supervisor starts them again after they exit.
+    #[serde(default)]
+    restart: RestartPolicy,
+}
+
+impl SphereProcess {
+    /// Checks that the manifest declares exactly one of `entrypoint` and `[[steps]]`.
+    fn check_runnable(&self, file_path: &Path) -> Result<(), Box<dyn Error>> {
+        match (&self.entrypoint, &self.steps) {
+            (None, None) => Err(format!("The file '{}' is missing the required 'entrypoint' field.", file_path.display()).into()),
+            (Some(_), Some(_)) => Err(format!("The file '{}' declares both 'entrypoint' and [[steps]]; use one or the other.", file_path.display()).into()),
+            (None, Some(steps)) if steps.is_empty() => Err(format!("The file '{}' declares an empty list of [[steps]].", file_path.display()).into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// One step of a pipeline. `run` takes the same forms as `entrypoint`; `env` is layered over the
+/// sphere's `[env]` and `outputs` are collected as soon as the step succeeds.
+#[derive(Deserialize, Debug, Clone)]
+struct SphereStep {
+    name: String,
+    run: Entrypoint,
+    #[serde(default)]
+    needs: Vec<String>,
+    env: Option<BTreeMap<String, String>>,
+    outputs: Option<Vec<String>>,
+}
+
+/// A `[dependencies]` entry: an exact Sphere ID such as "com.util.greeter/v1.0.0", or a package
+/// ID with a semver requirement such as `{ id = "com.util.greeter", version = "^1.2" }`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum DependencySpec {
+    Exact(String),
+    Versioned { id: String, version: String },
+}
+
+/// A shell script string, or an argv array that is executed without a shell.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Entrypoint {
+    Script(String),
+    Argv(Vec<String>),
+}
+
+impl std::fmt::Display for Entrypoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Entrypoint::Script(script) => f.write_str(script),
+            Entrypoint::Argv(argv) => f.write_str(&argv.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>().join(" ")),
+        }
+    }
+}
+
+/// A host file or directory copied into the workspace before the entrypoint starts.
+/// `from` is relative to the manifest's directory, `to` relative to the workspace.
+#[derive(Deserialize, Debug, Clone)]
+struct SphereInput {
+    from: String,
+    to: String,
+}
+
+/// Resource limits for the entrypoint and everything it starts. They are applied as rlimits,
+/// and memory and process counts are also enforced through a cgroup when one can be created.
+#[derive(Deserialize, Debug, Default, Clone)]
+struct SphereLimits {
+    cpu_seconds: Option<u64>,
+    memory_mb: Option<u64>,
+    max_processes: Option<u64>,
+    max_file_size_mb: Option<u64>,
+    open_files: Option<u64>,
+}
+
+/// A `task` runs once, in the foreground; a `service` is meant to stay up in the background,
+/// started with `sphere up`.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SphereKind {
+    #[default]
+    Task,
+    Service,
+}
+
+/// When a service is started again after its run ends.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum RestartPolicy {
+    #[default]
+    No,
+    OnFailure,
+    Always,
+}
+
+/// How the wait between attempts grows: `fixed` waits `retry_delay` every time, `exponential`
+/// doubles it after each attempt.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum RetryBackoff {
+    #[default]
+    Fixed,
+    Exponential,
+}
+
+/// Network access inside the sandbox. `none` gives the sphere an empty network namespace,
+/// `loopback` the same namespace with `lo` brought up, and `host` shares the host's network.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum NetworkPolicy {
+    #[default]
+    None,
+    Loopback,
+    Host,
+}
+
+impl NetworkPolicy {
+    fn as_str(self) -> &'static str {
+        match self {
+            NetworkPolicy::None => "none",
+            NetworkPolicy::Loopback => "loopback",
+            NetworkPolicy::Host => "host",
+        }
+    }
+}
+
+/// Host paths a sphere may access. Paths are absolute or relative to the manifest's directory
+/// and appear at the same absolute location inside the sandbox; everything else stays invisible.
+#[derive(Deserialize, Debug, Default)]
+struct SpherePermissions {
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
 }
 
+/// A dependency in the resolved graph. Each Sphere ID appears once, however many spheres use it.
 struct Dependency {
-    alias: String,
+    id: String,
+    /// Where the definition came from: "cache:<index entry>" or "hub:<filename>".
+    source: String,
+    sha256: String,
     process: SphereProcess,
+    /// The dependency's own aliases, as indexes into the resolved graph.
+    dependencies: Vec<(String, usize)>,
 }
 
 #[derive(Deserialize, Debug, Clone)] 
@@ -94,6 +383,87 @@ struct HubSphereInfo {
     hash_sha256: String,
 }
 
+/// Options for `sphere run` that come from the command line rather than the manifest.
+struct RunOptions {
+    no_sandbox: bool,
+    timeout: Option<Duration>,
+    output_mode: OutputMode,
+    no_stdin: bool,
+    env: Vec<(String, String)>,
+    out_dir: Option<PathBuf>,
+    keep_sandbox: bool,
+    workdir: Option<PathBuf>,
+    workdir_reuse: bool,
+    locked: bool,
+    offline: bool,
+    jobs: NonZeroUsize,
+    step: Option<String>,
+    report: Option<PathBuf>,
+    format: ReportFormat,
+    args: Vec<String>,
+}
+
+impl RunOptions {
+    /// Whether the run's output is kept for a report, on top of being shown as usual.
+    fn captures_output(&self) -> bool {
+        self.report.is_some() || self.format == ReportFormat::Json
+    }
+}
+
+/// An error that makes `sphere` exit with a specific status code instead of 1.
+#[derive(Debug)]
+struct ExitCodeError {
+    code: i32,
+    message: String,
+}
+
+impl std::fmt::Display for ExitCodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ExitCodeError {}
+
+/// The status `sphere` exits with for `result`.
+fn result_exit_code(result: &Result<(), Box<dyn Error>>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.downcast_ref::<ExitCodeError>().map_or(1, |e| e.code),
+    }
+}
+
+// --- Duration Parsing ---
+/// Parses durations such as "500ms", "30s", "5m", "1.5h" or a bare number of seconds.
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split_at = text.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(text.len());
+    let (number, unit) = text.split_at(split_at);
+    let value: f64 = number.parse().map_err(|_| format!("Invalid duration '{}': expected a number followed by ms, s, m or h.", text))?;
+    let seconds = match unit.trim() {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        other => return Err(format!("Invalid duration '{}': unknown unit '{}' (use ms, s, m or h).", text, other)),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("Invalid duration '{}'.", text))
+}
+
+/// Accepts either an integer number of seconds or a duration string in a manifest.
+fn deserialize_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawDuration {
+        Seconds(u64),
+        Text(String),
+    }
+    match Option::<RawDuration>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawDuration::Seconds(seconds)) => Ok(Some(Duration::from_secs(seconds))),
+        Some(RawDuration::Text(text)) => parse_duration(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
 
 // --- Helper Functions for Cache Management ---
 fn get_cache_paths() -> Result<(PathBuf, PathBuf), Box<dyn Error>> {
@@ -272,6 +642,7 @@ fn handle_sphere_publish(file_path: &PathBuf, quiet: bool) -> Result<(), Box<dyn
         .map_err(|e| format!("Failed to read sphere file '{}': {}", file_path.display(), e))?;
     let sphere_process: SphereProcess = toml::from_str(&content_string)
         .map_err(|e| format!("Failed to parse TOML from '{}': {}", file_path.display(), e))?;
+    sphere_process.check_runnable(file_path)?;
 
     let sphere_id = match &sphere_process.id {
         Some(id_val) if !id_val.trim().is_empty() => id_val.trim().to_string(),
@@ -354,6 +725,19 @@ fn handle_sphere_publish(file_path: &PathBuf, quiet: bool) -> Result<(), Box<dyn
 }
 
 // --- SphereHub Fetching Logic ---
+fn fetch_hub_master_index(http_client: &Client) -> Result<HashMap<String, HubSphereInfo>, Box<dyn Error>> {
+    let master_index_url = format!("{}index.json", SPHEREHUB_REGISTRY_URL);
+    let response = http_client.get(&master_index_url).send()?;
+    if !response.status().is_success() {
+        return Err(format!("Failed to fetch SphereHub master index from '{}': HTTP {}", master_index_url, response.status()).into());
+    }
+    let response_text = response.text()?;
+    
+    let master_index: HashMap<String, HubSphereInfo> = serde_json::from_str(&response_text)
+        .map_err(|e| format!("Failed to parse SphereHub master index: {}. Content: '{}'", e, response_text))?;
+    Ok(master_index)
+}
+
 fn fetch_sphere_from_hub(
     sphere_id: &str,
     local_cache_dir: &Path,
@@ -367,14 +751,7 @@ fn fetch_sphere_from_hub(
     }
 
     let master_index_url = format!("{}index.json", SPHEREHUB_REGISTRY_URL);
-    let response = http_client.get(&master_index_url).send()?;
-    if !response.status().is_success() {
-        return Err(format!("Failed to fetch SphereHub master index from '{}': HTTP {}", master_index_url, response.status()).into());
-    }
-    let response_text = response.text()?;
-    
-    let master_index: HashMap<String, HubSphereInfo> = serde_json::from_str(&response_text)
-        .map_err(|e| format!("Failed to parse SphereHub master index: {}. Content: '{}'", e, response_text))?;
+    let master_index = fetch_hub_master_index(http_client)?;
 
     let hub_info = master_index.get(sphere_id).ok_or_else(|| {
         format!("Sphere ID '{}' not found in the public SphereHub registry at {}.", sphere_id, master_index_url)
@@ -422,228 +799,4279 @@ fn fetch_sphere_from_hub(
     Ok(local_sphere_file_path)
 }
 
+// --- Dependency Resolution ---
+/// Walks the dependency graph depth-first through the local cache and SphereHub, loading each
+/// Sphere ID once and rejecting cycles.
+struct DependencyResolver {
+    cache_dir: PathBuf,
+    index_path: PathBuf,
+    local_index: HashMap<String, String>,
+    /// Created on first use, and never in offline mode.
+    http_client: Option<Client>,
+    offline: bool,
+    quiet: bool,
+    resolved: Vec<Dependency>,
+    /// Dependencies that offline mode could not find locally, collected so all are reported at once.
+    missing: Vec<String>,
+    /// SphereHub's master index, fetched the first time a version has to be chosen.
+    hub_index: Option<HashMap<String, HubSphereInfo>>,
+    /// Every version requirement met so far in this pass, by package ID.
+    requirements: HashMap<String, Vec<VersionRequirement>>,
+    /// The version chosen for each package in this pass. A package has one version per graph.
+    selected: HashMap<String, semver::Version>,
+    /// Versions fixed by an earlier pass that ran into a conflict.
+    pinned: HashMap<String, semver::Version>,
+}
 
-// --- Main Application Logic for 'sphere run' ---
-fn run_sphere(file_path: &Path, quiet: bool) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(file_path)
-        .map_err(|e| format!("Failed to read sphere file '{}': {}", file_path.display(), e))?;
-    let sphere_process: SphereProcess = toml::from_str(&content)
-        .map_err(|e| format!("Failed to parse TOML from '{}': {}", file_path.display(), e))?;
-    
-    if !quiet {
-        println!("-> Parsed entrypoint: '{}' from '{}'", &sphere_process.entrypoint, file_path.display());
-        if let Some(id) = &sphere_process.id {
-            println!("   Sphere ID: {}", id);
-        }
+/// A requirement on a package's version, remembered to explain conflicts.
+struct VersionRequirement {
+    requirement: semver::VersionReq,
+    declared_as: String,
+    alias: String,
+    required_by: String,
+}
+
+/// Returned when a conflict was fixed by pinning a version and the graph must be walked again.
+#[derive(Debug)]
+struct ResolutionRestart;
+
+impl std::fmt::Display for ResolutionRestart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("dependency resolution restarted")
     }
+}
 
-    let mut resolved_deps: Vec<Dependency> = Vec::new();
-    if let Some(deps) = &sphere_process.dependencies {
-        if !quiet {
-            println!("-> Resolving dependencies...");
-        }
-        let (cache_dir, local_index_path) = get_cache_paths()?;
-        let mut local_index = load_cache_index(&local_index_path)?;
-        
-        if !quiet && !local_index.is_empty() {
-             println!("   - Loaded local cache index from '{}'.", local_index_path.display());
-        } else if !quiet && local_index.is_empty() {
-             println!("   - Local cache index at '{}' is empty or not found.", local_index_path.display());
-        }
-        
-        let http_client = Client::builder()
+impl Error for ResolutionRestart {}
+
+/// Upper bound on resolution passes; each pass after the first follows a newly pinned version.
+const MAX_RESOLUTION_PASSES: usize = 32;
+
+/// The HTTP client in `slot`, built on first use so offline runs never create one.
+fn hub_client(slot: &mut Option<Client>) -> Result<&Client, Box<dyn Error>> {
+    match slot {
+        Some(client) => Ok(client),
+        None => Ok(slot.insert(Client::builder()
             .user_agent(format!("sphere-cli/{}", env!("CARGO_PKG_VERSION")))
-            .build()?;
+            .build()?)),
+    }
+}
 
-        for (alias, sphere_id) in deps {
-            let dep_path: PathBuf; // Removed 'mut' as per clippy
+/// Splits a Sphere ID like "com.util.greeter/v1.2.0" into its package ID and version.
+fn split_versioned_id(sphere_id: &str) -> Option<(&str, semver::Version)> {
+    let (package, version) = sphere_id.rsplit_once('/')?;
+    let version = semver::Version::parse(version.strip_prefix('v').unwrap_or(version)).ok()?;
+    Some((package, version))
+}
 
-            if let Some(dep_filename_in_local_cache) = local_index.get(sphere_id) {
-                let current_dep_path = if Path::new(dep_filename_in_local_cache).is_absolute() {
-                    PathBuf::from(dep_filename_in_local_cache)
-                } else {
-                    cache_dir.join(dep_filename_in_local_cache)
-                };
+impl DependencyResolver {
+    fn new(offline: bool, quiet: bool) -> Result<Self, Box<dyn Error>> {
+        let (cache_dir, index_path) = get_cache_paths()?;
+        let local_index = load_cache_index(&index_path)?;
+        if !quiet && !local_index.is_empty() {
+             println!("   - Loaded local cache index from '{}'.", index_path.display());
+        } else if !quiet && local_index.is_empty() {
+             println!("   - Local cache index at '{}' is empty or not found.", index_path.display());
+        }
+        Ok(DependencyResolver {
+            cache_dir, index_path, local_index, offline, quiet,
+            http_client: None,
+            resolved: Vec::new(),
+            missing: Vec::new(),
+            hub_index: None,
+            requirements: HashMap::new(),
+            selected: HashMap::new(),
+            pinned: HashMap::new(),
+        })
+    }
 
-                if !current_dep_path.exists() {
-                    if !quiet {
-                        println!("   - Dependency '{}' (Sphere ID: '{}') found in local index but file missing at '{}'. Attempting Hub fetch.", alias, sphere_id, current_dep_path.display());
-                    }
-                    dep_path = fetch_sphere_from_hub(sphere_id, &cache_dir, &local_index_path, &mut local_index, &http_client, quiet)?;
-                } else {
-                    if !quiet {
-                        println!("   - Using locally cached dependency '{}' (Sphere ID: '{}') from '{}'", alias, sphere_id, current_dep_path.display());
-                    }
-                    dep_path = current_dep_path;
+    /// Resolves the graph below the root sphere `root` and returns the root's aliases.
+    /// A version conflict that some other version can satisfy pins that version and starts over.
+    fn resolve_graph(&mut self, deps: &HashMap<String, DependencySpec>, root: &str) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
+        for _ in 0..MAX_RESOLUTION_PASSES {
+            self.resolved.clear();
+            self.requirements.clear();
+            self.selected.clear();
+            self.missing.clear();
+            match self.resolve_all(deps, &mut vec![root.to_string()]) {
+                Err(e) if e.is::<ResolutionRestart>() => continue,
+                Ok(_) if !self.missing.is_empty() => {
+                    return Err(format!(
+                        "Offline mode: {} dependenc{} not available locally:\n   - {}\n   Add them with 'sphere cache add' or run once without --offline.",
+                        self.missing.len(), if self.missing.len() == 1 { "y is" } else { "ies are" }, self.missing.join("\n   - ")
+                    ).into());
                 }
-            } else {
-                dep_path = fetch_sphere_from_hub(sphere_id, &cache_dir, &local_index_path, &mut local_index, &http_client, quiet)?;
-            }
-            
-            if !quiet && dep_path.exists() {
-                println!("   - Loading dependency definition for '{}' from '{}'", alias, dep_path.display());
-            } else if !dep_path.exists() {
-                 return Err(format!("Failed to obtain dependency '{}' (Sphere ID: '{}'). Expected at '{}' after attempting local cache and Hub fetch.", alias, sphere_id, dep_path.display()).into());
-            }
-
-            let dep_content = fs::read_to_string(&dep_path)
-                .map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::NotFound {
-                        Box::new(std::io::Error::other(format!( // Used Error::other
-                            "Dependency file for '{}' (Sphere ID: '{}', alias: '{}') not found at expected path '{}' even after cache/Hub check. This indicates an inconsistency.",
-                            dep_path.display(), sphere_id, alias, dep_path.display()
-                        ))) as Box<dyn Error>
-                    } else {
-                        Box::new(std::io::Error::other(format!( // Used Error::other
-                            "Failed to read dependency file '{}' (Sphere ID: '{}', alias: '{}'): {}", 
-                            dep_path.display(), sphere_id, alias, e
-                        ))) as Box<dyn Error>
-                    }
-                })?;
-            let dep_process: SphereProcess = toml::from_str(&dep_content)
-                .map_err(|e| format!("Failed to parse TOML for dependency '{}' (file: {}): {}", sphere_id, dep_path.display(), e))?;
-
-            resolved_deps.push(Dependency {
-                alias: alias.clone(),
-                process: dep_process,
-            });
+                result => return result,
+            }
         }
+        Err(format!("Dependency versions could not be settled after {} attempts; the graph's requirements keep changing with the versions chosen.", MAX_RESOLUTION_PASSES).into())
     }
 
-    let temp_dir = tempdir()?;
-    if !quiet {
-        println!("-> Created secure sandbox at: {:?}", temp_dir.path());
+    /// Resolves every alias in `deps`. `chain` holds the IDs of the spheres that led here, from
+    /// the root down to the sphere declaring `deps`.
+    fn resolve_all(&mut self, deps: &HashMap<String, DependencySpec>, chain: &mut Vec<String>) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
+        let mut aliases: Vec<(&String, &DependencySpec)> = deps.iter().collect();
+        aliases.sort_by_key(|(alias, _)| *alias);
+        let mut resolved = Vec::new();
+        for (alias, spec) in aliases {
+            let Some(sphere_id) = self.select(alias, spec, chain)? else { continue };
+            if let Some(index) = self.resolve(alias, &sphere_id, chain)? {
+                resolved.push((alias.clone(), index));
+            }
+        }
+        Ok(resolved)
     }
-    let bin_path = temp_dir.path().join("bin");
-    fs::create_dir(&bin_path)?;
 
-    for dep in &resolved_deps {
-        let script_path = bin_path.join(&dep.alias);
-        let mut script_file = fs::File::create(&script_path)?;
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = script_file.metadata()?.permissions();
-            perms.set_mode(0o755);
-            fs::set_permissions(&script_path, perms)?;
+    /// Picks the Sphere ID for a dependency. Versioned IDs, exact or with a requirement, join the
+    /// package's requirements; other IDs are used as they are. Returns `None` when offline mode
+    /// has no cached version that meets the requirement.
+    fn select(&mut self, alias: &str, spec: &DependencySpec, chain: &[String]) -> Result<Option<String>, Box<dyn Error>> {
+        let (package, requirement, exact_id) = match spec {
+            DependencySpec::Exact(sphere_id) => match split_versioned_id(sphere_id) {
+                Some((package, version)) => (package, semver::VersionReq::parse(&format!("={}", version))?, Some(sphere_id)),
+                None => return Ok(Some(sphere_id.clone())),
+            },
+            DependencySpec::Versioned { id, version } => {
+                let requirement = semver::VersionReq::parse(version)
+                    .map_err(|e| format!("Dependency '{}' has an invalid version requirement '{}' for '{}': {}", alias, version, id, e))?;
+                (id.as_str(), requirement, None)
+            }
+        };
+        self.requirements.entry(package.to_string()).or_default().push(VersionRequirement {
+            requirement: requirement.clone(),
+            declared_as: match spec {
+                DependencySpec::Exact(sphere_id) => sphere_id.clone(),
+                DependencySpec::Versioned { version, .. } => version.clone(),
+            },
+            alias: alias.to_string(),
+            required_by: chain.join(" -> "),
+        });
+
+        if let Some(version) = self.selected.get(package).cloned() {
+            if requirement.matches(&version) {
+                return Ok(Some(match exact_id {
+                    Some(sphere_id) => sphere_id.clone(),
+                    None => self.versioned_id(package, &version),
+                }));
+            }
+            let version = self.satisfying_version(package)?;
+            if !self.quiet {
+                println!("   - Version conflict on '{}'; retrying with v{}", package, version);
+            }
+            self.pinned.insert(package.to_string(), version);
+            return Err(Box::new(ResolutionRestart));
+        }
+        let version = match self.pinned.get(package) {
+            Some(pinned) if requirement.matches(pinned) => pinned.clone(),
+            _ => match exact_id {
+                Some(sphere_id) => return Ok(Some(self.select_exact(package, sphere_id))),
+                None if self.offline && !self.available_versions(package).iter().any(|(version, _)| requirement.matches(version)) => {
+                    let cached: Vec<String> = self.available_versions(package).iter().map(|(version, _)| version.to_string()).collect();
+                    let reason = if cached.is_empty() {
+                        "no version is cached".to_string()
+                    } else {
+                        format!("no cached version matches (cached: {})", cached.join(", "))
+                    };
+                    self.missing.push(format!("'{}' {} (alias '{}' in {}): {}", package, requirement, alias, chain.join(" -> "), reason));
+                    return Ok(None);
+                }
+                None => self.satisfying_version(package)?,
+            },
+        };
+        if !self.quiet {
+            println!("   - Selected v{} of '{}' for '{}' ({})", version, package, alias, requirement);
         }
-        writeln!(script_file, "#!/bin/sh")?;
-        writeln!(script_file, "{}", dep.process.entrypoint)?;
+        self.selected.insert(package.to_string(), version.clone());
+        Ok(Some(self.versioned_id(package, &version)))
     }
-    
-    let original_path = std::env::var("PATH").unwrap_or_default();
-    let new_path = format!("{}:{}", bin_path.to_string_lossy(), original_path);
-    
-    if !quiet {
-        println!("-> Executing entrypoint inside sandbox...");
-    }
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(&sphere_process.entrypoint)
-        .current_dir(temp_dir.path())
-        .env("PATH", new_path)
-        .output()?;
-    if !quiet {
-        println!("-> Execution finished.\n");
+
+    fn select_exact(&mut self, package: &str, sphere_id: &str) -> String {
+        if let Some((_, version)) = split_versioned_id(sphere_id) {
+            self.selected.insert(package.to_string(), version);
+        }
+        sphere_id.to_string()
     }
-    
-    if !quiet { 
-        println!("--- Command STDOUT ---");
+
+    /// The highest available version of `package` that meets every requirement on it so far.
+    fn satisfying_version(&mut self, package: &str) -> Result<semver::Version, Box<dyn Error>> {
+        let available = self.available_versions(package);
+        let requirements = &self.requirements[package];
+        if let Some((version, _)) = available.iter().rev().find(|(version, _)| requirements.iter().all(|r| r.requirement.matches(version))) {
+            return Ok(version.clone());
+        }
+        let mut message = if requirements.len() == 1 {
+            format!("Dependency '{}' requires '{}' {}, but no available version matches.", requirements[0].alias, package, requirements[0].declared_as)
+        } else {
+            format!("Dependency version conflict on '{}': no version satisfies every requirement:", package)
+        };
+        if requirements.len() > 1 {
+            for r in requirements {
+                message.push_str(&format!("\n   - {} (alias '{}' in {})", r.declared_as, r.alias, r.required_by));
+            }
+        }
+        let versions: Vec<String> = available.iter().map(|(version, _)| version.to_string()).collect();
+        message.push_str(&format!("\n   Available versions: {}", if versions.is_empty() { "none".to_string() } else { versions.join(", ") }));
+        Err(message.into())
     }
-    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    if !stdout.is_empty() {
-        println!("{}", stdout);
-    } else if !quiet { 
-        println!("(empty)");
+
+    /// Versions of `package` listed in the local cache index or on SphereHub, lowest first,
+    /// each with the Sphere ID it is published under.
+    fn available_versions(&mut self, package: &str) -> Vec<(semver::Version, String)> {
+        if self.hub_index.is_none() && !self.offline {
+            let index = hub_client(&mut self.http_client).and_then(fetch_hub_master_index);
+            self.hub_index = Some(index.unwrap_or_else(|e| {
+                if !self.quiet {
+                    println!("   - Could not read the SphereHub index ({}); choosing among cached versions only.", e);
+                }
+                HashMap::new()
+            }));
+        }
+        let hub_ids = self.hub_index.iter().flat_map(|index| index.keys());
+        let mut versions: Vec<(semver::Version, String)> = self.local_index.keys().chain(hub_ids)
+            .filter_map(|sphere_id| match split_versioned_id(sphere_id) {
+                Some((candidate, version)) if candidate == package => Some((version, sphere_id.clone())),
+                _ => None,
+            })
+            .collect();
+        versions.sort();
+        versions.dedup_by(|a, b| a.0 == b.0);
+        versions
     }
-    if !quiet {
-        println!("----------------------");
+
+    fn versioned_id(&mut self, package: &str, version: &semver::Version) -> String {
+        self.available_versions(package).into_iter()
+            .find(|(candidate, _)| candidate == version)
+            .map(|(_, sphere_id)| sphere_id)
+            .unwrap_or_else(|| format!("{}/v{}", package, version))
     }
 
-    if !output.stderr.is_empty() {
-        if !quiet {
-            println!("\n--- Command STDERR ---");
+    fn resolve(&mut self, alias: &str, sphere_id: &str, chain: &mut Vec<String>) -> Result<Option<usize>, Box<dyn Error>> {
+        if chain.iter().any(|id| id == sphere_id) {
+            let path: Vec<&str> = chain.iter().map(String::as_str).chain([sphere_id]).collect();
+            return Err(format!("Dependency cycle detected: {} (via alias '{}')", path.join(" -> "), alias).into());
         }
-        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
-        println!("{}", stderr); 
-        if !quiet {
-            println!("----------------------");
+        if let Some(index) = self.resolved.iter().position(|dep| dep.id == sphere_id) {
+            if !self.quiet {
+                println!("   - Reusing dependency '{}' (Sphere ID: '{}') already required elsewhere in the graph", alias, sphere_id);
+            }
+            return Ok(Some(index));
         }
+
+        let Some((dep_path, source)) = self.locate(alias, sphere_id, chain)? else { return Ok(None) };
+        let dep_content = fs::read_to_string(&dep_path)
+            .map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    Box::new(std::io::Error::other(format!( // Used Error::other
+                        "Dependency file for '{}' (Sphere ID: '{}', alias: '{}') not found at expected path '{}' even after cache/Hub check. This indicates an inconsistency.",
+                        dep_path.display(), sphere_id, alias, dep_path.display()
+                    ))) as Box<dyn Error>
+                } else {
+                    Box::new(std::io::Error::other(format!( // Used Error::other
+                        "Failed to read dependency file '{}' (Sphere ID: '{}', alias: '{}'): {}", 
+                        dep_path.display(), sphere_id, alias, e
+                    ))) as Box<dyn Error>
+                }
+            })?;
+        let dep_process: SphereProcess = toml::from_str(&dep_content)
+            .map_err(|e| format!("Failed to parse TOML for dependency '{}' (file: {}): {}", sphere_id, dep_path.display(), e))?;
+        if dep_process.entrypoint.is_none() {
+            return Err(format!("Dependency '{}' (Sphere ID: '{}') has no entrypoint; a Sphere made of [[steps]] cannot be used as a dependency.", alias, sphere_id).into());
+        }
+
+        let nested = dep_process.dependencies.clone().unwrap_or_default();
+        chain.push(sphere_id.to_string());
+        let dependencies = self.resolve_all(&nested, chain)?;
+        chain.pop();
+        self.resolved.push(Dependency {
+            id: sphere_id.to_string(),
+            source,
+            sha256: format!("{:x}", Sha256::digest(dep_content.as_bytes())),
+            process: dep_process,
+            dependencies,
+        });
+        Ok(Some(self.resolved.len() - 1))
     }
-    Ok(())
-}
 
-// --- Main function: Parses CLI args and dispatches to handlers ---
-fn main() {
-    let cli = Cli::parse();
+    /// Finds the `.sphere` file for `sphere_id` in the local cache, fetching it from SphereHub
+    /// when it is not cached or the cached file has gone missing. Also returns its source.
+    /// In offline mode a dependency that is not cached is recorded as missing instead.
+    fn locate(&mut self, alias: &str, sphere_id: &str, chain: &[String]) -> Result<Option<(PathBuf, String)>, Box<dyn Error>> {
+        let dep_path: PathBuf; // Removed 'mut' as per clippy
+        let mut source = None;
 
-    let result = match &cli.command { 
-        Commands::Run { file_path } => {
-            run_sphere(file_path, cli.quiet)
-        }
-        Commands::Cache { action } => match action { 
-            CacheAction::List => {
-                handle_cache_list(cli.quiet)
-            }
-            CacheAction::Add { id, sphere_file_path, copy_to_cache } => {
-                handle_cache_add(id, sphere_file_path, *copy_to_cache, cli.quiet)
-            }
-            CacheAction::Remove { id } => {
-                handle_cache_remove(id, cli.quiet)
+        if self.offline {
+            let cached = self.local_index.get(sphere_id).map(|entry| self.cache_dir.join(entry));
+            if !cached.as_ref().is_some_and(|path| path.exists()) {
+                let reason = match cached {
+                    Some(path) => format!("cached file '{}' is missing", path.display()),
+                    None => "not in the local cache".to_string(),
+                };
+                self.missing.push(format!("'{}' (alias '{}' in {}): {}", sphere_id, alias, chain.join(" -> "), reason));
+                return Ok(None);
             }
-        },
-        Commands::Publish { file_path } => { 
-            handle_sphere_publish(file_path, cli.quiet)
         }
-    };
 
-    if let Err(e) = result {
-        let mut error_message = format!("{}", e);
-        let mut specific_error_handled = false;
-        let mut file_path_for_error: Option<String> = None;
+        if let Some(dep_filename_in_local_cache) = self.local_index.get(sphere_id) {
+            let current_dep_path = if Path::new(dep_filename_in_local_cache).is_absolute() {
+                PathBuf::from(dep_filename_in_local_cache)
+            } else {
+                self.cache_dir.join(dep_filename_in_local_cache)
+            };
 
-        match &cli.command {
-            Commands::Run { file_path } => {
-                file_path_for_error = Some(file_path.display().to_string());
-            }
-            Commands::Publish { file_path } => {
-                 file_path_for_error = Some(file_path.display().to_string());
-            }
-            Commands::Cache { action } => {
-                if let CacheAction::Add { sphere_file_path, .. } = action {
-                    file_path_for_error = Some(sphere_file_path.display().to_string());
+            if !current_dep_path.exists() {
+                if !self.quiet {
+                    println!("   - Dependency '{}' (Sphere ID: '{}') found in local index but file missing at '{}'. Attempting Hub fetch.", alias, sphere_id, current_dep_path.display());
                 }
+                dep_path = fetch_sphere_from_hub(sphere_id, &self.cache_dir, &self.index_path, &mut self.local_index, hub_client(&mut self.http_client)?, self.quiet)?;
+            } else {
+                if !self.quiet {
+                    println!("   - Using locally cached dependency '{}' (Sphere ID: '{}') from '{}'", alias, sphere_id, current_dep_path.display());
+                }
+                source = Some(format!("cache:{}", dep_filename_in_local_cache));
+                dep_path = current_dep_path;
             }
+        } else {
+            dep_path = fetch_sphere_from_hub(sphere_id, &self.cache_dir, &self.index_path, &mut self.local_index, hub_client(&mut self.http_client)?, self.quiet)?;
         }
 
-        if let Some(toml_error) = e.downcast_ref::<toml::de::Error>() {
-            let path_str = file_path_for_error.as_deref().unwrap_or("the specified .sphere file");
-            if toml_error.message().contains("missing field `entrypoint`") {
-                error_message = format!("The file '{}' is missing the required 'entrypoint' field.", path_str);
-                specific_error_handled = true;
-            } else { 
-                 error_message = format!("Failed to parse TOML from '{}'. Reason: {}", path_str, toml_error);
-                 specific_error_handled = true;
-            }
+        if !self.quiet && dep_path.exists() {
+            println!("   - Loading dependency definition for '{}' from '{}'", alias, dep_path.display());
+        } else if !dep_path.exists() {
+             return Err(format!("Failed to obtain dependency '{}' (Sphere ID: '{}'). Expected at '{}' after attempting local cache and Hub fetch.", alias, sphere_id, dep_path.display()).into());
         }
-        
-        if !specific_error_handled {
-            let custom_prefixes = [
-                "Dependency", "Failed to read sphere file", "Failed to parse TOML from",
-                "Sphere ID", "Source file", "A file named", "Failed to copy",
-                "Failed to get absolute path", "Failed to save cache index",
-                "Failed to parse cache index", "Could not determine home directory",
-                "Cannot derive a valid cache filename", "Failed to fetch SphereHub master index",
-                /* "Sphere ID" is too generic, use more specific part of the error message */
-                "not found in the public SphereHub registry", "Failed to fetch Sphere file",
-                "Hash mismatch for Sphere", "Failed to save downloaded Sphere"
-            ];
-            if !custom_prefixes.iter().any(|p| e.to_string().contains(p)) { // Changed to .contains() for broader matching
-                error_message = format!("Application error: {}", e);
+        let source = source.unwrap_or_else(|| format!("hub:{}", dep_path.file_name().unwrap_or_default().to_string_lossy()));
+        Ok(Some((dep_path, source)))
+    }
+
+    /// Prefers the versions recorded in a lockfile, as long as the graph's requirements allow them.
+    fn pin_locked(&mut self, locked: &[LockedDependency]) {
+        for entry in locked {
+            if let Some((package, version)) = split_versioned_id(&entry.id) {
+                self.pinned.insert(package.to_string(), version);
             }
         }
-        
-        eprintln!("\nError: {}", error_message.trim());
-        std::process::exit(1);
+    }
+}
+
+// --- Lockfile ---
+const LOCKFILE_NAME: &str = "sphere.lock";
+const LOCKFILE_HEADER: &str = "# Generated by `sphere run`. Records the exact dependencies each Sphere in this directory resolved to.\n";
+
+/// `sphere.lock`, shared by every manifest in its directory and keyed by manifest file name.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct LockFile {
+    version: u32,
+    #[serde(default)]
+    spheres: BTreeMap<String, Vec<LockedDependency>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct LockedDependency {
+    id: String,
+    source: String,
+    sha256: String,
+}
+
+impl LockFile {
+    fn load(path: &Path) -> Result<Option<LockFile>, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Lockfile '{}' could not be read: {}", path.display(), e))?;
+        let lock = toml::from_str(&content)
+            .map_err(|e| format!("Lockfile '{}' is not valid: {}", path.display(), e))?;
+        Ok(Some(lock))
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let content = format!("{}{}", LOCKFILE_HEADER, toml::to_string(self)?);
+        fs::write(path, content).map_err(|e| format!("Lockfile '{}' could not be written: {}", path.display(), e).into())
+    }
+}
+
+/// Whether a dependency comes from a file registered with `sphere cache add` without copying it.
+fn is_registered_in_place(source: &str) -> bool {
+    source.strip_prefix("cache:").is_some_and(|entry| Path::new(entry).is_absolute())
+}
+
+/// Whether two lockfile sources name the same origin. A SphereHub download is kept in the cache
+/// under its hub filename, so "hub:<file>" and "cache:<file>" are the same file, whichever of the
+/// two a machine happened to read it from.
+fn same_source(locked: &str, resolved: &str) -> bool {
+    let cached_download = |source: &str| match source.split_once(':') {
+        Some(("hub", file)) => Some(file.to_string()),
+        Some(("cache", entry)) if !Path::new(entry).is_absolute() => Some(entry.to_string()),
+        _ => None,
+    };
+    locked == resolved || cached_download(locked).is_some_and(|file| cached_download(resolved) == Some(file))
+}
+
+/// Compares the resolved graph with the lockfile entries for one manifest. A changed sha256 for a
+/// locked ID is an error, unless the Sphere is registered in place by absolute path and so is
+/// meant to be edited; that, and added or removed IDs, is drift, which `--locked` refuses and an
+/// ordinary run records. So is a locked ID that now comes from a different source.
+/// Returns whether the lockfile needs rewriting.
+fn verify_lock(locked: Option<&Vec<LockedDependency>>, resolved: &[LockedDependency], lock_path: &Path, locked_mode: bool) -> Result<bool, Box<dyn Error>> {
+    let Some(locked) = locked else {
+        if locked_mode {
+            return Err(format!("Lockfile '{}' has no entry for this Sphere and --locked was given; run once without --locked to create it.", lock_path.display()).into());
+        }
+        return Ok(true);
+    };
+    let mut tampered = Vec::new();
+    let mut drift = Vec::new();
+    for entry in resolved {
+        match locked.iter().find(|l| l.id == entry.id) {
+            Some(l) if l.sha256 != entry.sha256 && is_registered_in_place(&entry.source) => drift.push(format!("{} was edited in place", entry.id)),
+            Some(l) if l.sha256 != entry.sha256 => tampered.push(format!("{}: locked sha256 {}, resolved file has {}", entry.id, l.sha256, entry.sha256)),
+            Some(l) if !same_source(&l.source, &entry.source) => drift.push(format!("{} now comes from {} instead of {}", entry.id, entry.source, l.source)),
+            Some(_) => {}
+            None => drift.push(format!("{} is resolved but not locked", entry.id)),
+        }
+    }
+    for l in locked.iter().filter(|l| !resolved.iter().any(|entry| entry.id == l.id)) {
+        drift.push(format!("{} is locked but no longer resolved", l.id));
+    }
+    if !tampered.is_empty() {
+        return Err(format!(
+            "Lockfile '{}' does not match the dependency content on this machine:\n   - {}\n   Remove the affected entries from the lockfile if the change is expected.",
+            lock_path.display(), tampered.join("\n   - ")
+        ).into());
+    }
+    if !drift.is_empty() && locked_mode {
+        return Err(format!("Lockfile '{}' is out of date and --locked was given:\n   - {}", lock_path.display(), drift.join("\n   - ")).into());
+    }
+    Ok(!drift.is_empty())
+}
+
+// --- Sandbox Configuration ---
+/// Sandbox locations that spheres cannot request host paths for.
+const SANDBOX_RESERVED_PATHS: &[&str] = &["/sphere", "/proc", "/dev"];
+
+/// A host path bind-mounted into the sandbox at the same absolute location.
+struct HostPathGrant {
+    path: PathBuf,
+    writable: bool,
+}
+
+/// Per-run sandbox settings derived from the manifest.
+#[derive(Default)]
+struct SandboxConfig {
+    grants: Vec<HostPathGrant>,
+    network: NetworkPolicy,
+}
+
+/// Resolves the `[permissions]` table into canonical host paths, rejecting missing or reserved ones.
+/// A path listed under both `read` and `write` is granted write access.
+fn resolve_permissions(permissions: &SpherePermissions, manifest_dir: &Path) -> Result<Vec<HostPathGrant>, Box<dyn Error>> {
+    let mut grants: Vec<HostPathGrant> = Vec::new();
+    let requested = permissions.read.iter().map(|p| (p, false))
+        .chain(permissions.write.iter().map(|p| (p, true)));
+    for (declared, writable) in requested {
+        let host_path = manifest_dir.join(declared);
+        let canonical = fs::canonicalize(&host_path).map_err(|e| {
+            format!("Permission path '{}' (resolved to '{}') cannot be granted: {}", declared, host_path.display(), e)
+        })?;
+        if canonical == Path::new("/") || SANDBOX_RESERVED_PATHS.iter().any(|reserved| canonical.starts_with(reserved)) {
+            return Err(format!(
+                "Permission path '{}' (resolved to '{}') is not allowed: the host root and {} are reserved by the sandbox.",
+                declared, canonical.display(), SANDBOX_RESERVED_PATHS.join(", ")
+            ).into());
+        }
+        match grants.iter_mut().find(|g| g.path == canonical) {
+            Some(existing) => existing.writable |= writable,
+            None => grants.push(HostPathGrant { path: canonical, writable }),
+        }
+    }
+    // Mount parents before children so nested grants are not shadowed.
+    grants.sort_by_key(|g| g.path.components().count());
+    Ok(grants)
+}
+
+// --- Entrypoints and Shims ---
+/// File in the scripts directory holding a script entrypoint that runs under a custom interpreter.
+const ENTRYPOINT_SCRIPT: &str = "entrypoint";
+/// Where the sandbox mounts the scripts directory, read-only. It lies outside the workspace, so
+/// the scripts never show up among the sphere's files or outputs.
+const SANDBOX_SCRIPTS_DIR: &str = "/.sphere-internal";
+
+/// Quotes `text` for a POSIX shell.
+fn shell_quote(text: &str) -> String {
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c)) {
+        return text.to_string();
+    }
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// The `#!` line for a script run by `interpreter`; bare names are looked up in PATH through env.
+fn shebang(interpreter: Option<&str>) -> String {
+    match interpreter {
+        None => "#!/bin/sh".to_string(),
+        Some(path) if path.contains('/') => format!("#!{}", path),
+        Some(name) => format!("#!/usr/bin/env {}", name),
+    }
+}
+
+/// The program and arguments that start an argv entrypoint, prefixed by its interpreter if any.
+fn argv_command(process: &SphereProcess, argv: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    if argv.is_empty() {
+        return Err("The 'entrypoint' array must name a program to run.".into());
+    }
+    Ok(process.interpreter.iter().chain(argv).cloned().collect())
+}
+
+/// The full command line for the entrypoint or a step. Scripts run as `sh -c` by default, with
+/// `name` as "$0"; with an interpreter the script is written to `script_file` in `scripts_dir`
+/// and passed to it as a file, by its path in `scripts_dir_inside` as the sphere sees it.
+fn command_line(process: &SphereProcess, entrypoint: &Entrypoint, name: &str, script_file: &str, scripts_dir: &Path, scripts_dir_inside: &Path, shell: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    match (entrypoint, &process.interpreter) {
+        (Entrypoint::Script(script), None) => {
+            // The argument after the script becomes "$0".
+            Ok(vec![shell.to_string(), "-c".to_string(), script.clone(), name.to_string()])
+        }
+        (Entrypoint::Script(script), Some(interpreter)) => {
+            fs::write(scripts_dir.join(script_file), script)?;
+            Ok(vec![interpreter.clone(), scripts_dir_inside.join(script_file).to_string_lossy().into_owned()])
+        }
+        (Entrypoint::Argv(argv), _) => argv_command(process, argv),
+    }
+}
+
+fn write_executable(path: &Path, content: &str) -> io::Result<()> {
+    fs::write(path, content)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Directory under `bin/` holding one `<n>-<id>/` directory per resolved dependency, with its
+/// script entrypoint and a `bin/` of shims for its own aliases.
+const SHIM_DEPS_DIR: &str = ".deps";
+
+fn dependency_dir_name(index: usize, dependency: &Dependency) -> String {
+    format!("{}-{}", index, dependency.id.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-', "_"))
+}
+
+/// Writes the shims for the whole dependency graph: `bin/<alias>` for the sphere's own aliases,
+/// and `bin/.deps/<n>-<id>/bin/<alias>` for each dependency's, so every sphere sees its own aliases.
+/// `passed_through` names the host variables the running sphere received through `env_passthrough`.
+fn write_shims(bin_path: &Path, direct: &[(String, usize)], graph: &[Dependency], passed_through: &[String]) -> Result<(), Box<dyn Error>> {
+    let deps_dir = bin_path.join(SHIM_DEPS_DIR);
+    for (index, dependency) in graph.iter().enumerate() {
+        let dependency_dir = deps_dir.join(dependency_dir_name(index, dependency));
+        fs::create_dir_all(&dependency_dir)?;
+        if let Some(Entrypoint::Script(script)) = &dependency.process.entrypoint {
+            let content = format!("{}\n{}\n", shebang(dependency.process.interpreter.as_deref()), script);
+            write_executable(&dependency_dir.join("entrypoint"), &content)?;
+        }
+        if !dependency.dependencies.is_empty() {
+            fs::create_dir(dependency_dir.join("bin"))?;
+        }
+        for (alias, target) in &dependency.dependencies {
+            write_shim(&dependency_dir.join("bin"), "../..", alias, graph, *target, passed_through)?;
+        }
+    }
+    for (alias, target) in direct {
+        write_shim(bin_path, SHIM_DEPS_DIR, alias, graph, *target, passed_through)?;
+    }
+    Ok(())
+}
+
+/// Writes the executable `<dir>/<alias>` shim for `graph[index]`, where `deps_path` leads from
+/// `dir` to the `.deps` directory. The shim forwards its arguments and runs the dependency as
+/// `sphere run` would: from a clean environment holding only the caller's PATH (with the
+/// dependency's own aliases first), HOME and TMPDIR, SPHERE_ID, SPHERE_ALIAS and the dependency's
+/// own `[env]`. Its `env_passthrough` only reaches the `passed_through` variables, and only when
+/// the caller still has them: their values are looked up when the shim runs, never written into it.
+fn write_shim(dir: &Path, deps_path: &str, alias: &str, graph: &[Dependency], index: usize, passed_through: &[String]) -> Result<(), Box<dyn Error>> {
+    let dependency = &graph[index];
+    let process = &dependency.process;
+    let dependency_dir = format!("${{0%/*}}/{}/{}", deps_path, dependency_dir_name(index, dependency));
+    let mut environment = BTreeMap::new();
+    for (key, value) in process.env.iter().flatten() {
+        validate_env_name(key).map_err(|e| format!("Dependency '{}': {}", alias, e))?;
+        environment.insert(key.clone(), value.clone());
+    }
+    environment.insert("SPHERE_ID".to_string(), dependency.id.clone());
+    environment.insert("SPHERE_ALIAS".to_string(), alias.to_string());
+    let path = if dependency.dependencies.is_empty() { "$PATH".to_string() } else { format!("{}/bin:$PATH", dependency_dir) };
+    let mut command = vec!["exec /usr/bin/env -i".to_string()];
+    for pattern in process.env_passthrough.iter().flatten() {
+        validate_passthrough_pattern(pattern).map_err(|e| format!("Dependency '{}': {}", alias, e))?;
+    }
+    let forwarded = passed_through.iter()
+        .filter(|name| process.env_passthrough.iter().flatten().any(|pattern| passthrough_matches(pattern, name)))
+        .filter(|name| !environment.contains_key(*name));
+    // POSIX names can be expanded by the shim; others could never have reached its caller's shell anyway.
+    command.extend(forwarded
+        .filter(|name| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        .map(|name| format!("${{{0}+\"{0}=${0}\"}}", name)));
+    command.push(format!("PATH=\"{}\" HOME=\"$HOME\" TMPDIR=\"$TMPDIR\"", path));
+    command.extend(environment.iter().map(|(key, value)| shell_quote(&format!("{}={}", key, value))));
+    match &process.entrypoint {
+        None => return Err(format!("Dependency '{}' has no entrypoint.", alias).into()),
+        Some(Entrypoint::Script(_)) => command.push(format!("\"{}/entrypoint\"", dependency_dir)),
+        Some(Entrypoint::Argv(argv)) => {
+            let argv = argv_command(process, argv).map_err(|e| format!("Dependency '{}': {}", alias, e))?;
+            command.extend(argv.iter().map(|arg| shell_quote(arg)));
+        }
+    }
+    command.push("\"$@\"".to_string());
+    write_executable(&dir.join(alias), &format!("#!/bin/sh\n{}\n", command.join(" ")))?;
+    Ok(())
+}
+
+// --- Sphere Environment ---
+// The entrypoint starts from an empty environment. `sphere` sets PATH, HOME and TMPDIR to
+// locations inside the sandbox, then layers `env_passthrough`, `[env]` and `--env` on top.
+
+fn validate_env_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains('=') || name.contains('\0') {
+        return Err(format!("Environment variable name '{}' is invalid: it must be non-empty and contain no '=' or NUL.", name));
+    }
+    Ok(())
+}
+
+/// Parses a `--env KEY=VAL` argument.
+fn parse_env_assignment(text: &str) -> Result<(String, String), String> {
+    let (key, value) = text.split_once('=')
+        .ok_or_else(|| format!("Environment assignment '{}' must have the form KEY=VAL.", text))?;
+    validate_env_name(key)?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn validate_passthrough_pattern(pattern: &str) -> Result<(), String> {
+    match pattern.strip_suffix('*') {
+        Some("") => Ok(()),
+        prefix => validate_env_name(prefix.unwrap_or(pattern)),
+    }
+}
+
+/// Whether an `env_passthrough` pattern (a name, or a prefix ending in `*`) matches `name`.
+fn passthrough_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// The host variables a sphere's `env_passthrough` lets in.
+fn host_passthrough(process: &SphereProcess) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut environment = BTreeMap::new();
+    for pattern in process.env_passthrough.iter().flatten() {
+        validate_passthrough_pattern(pattern)?;
+        for (key, value) in std::env::vars_os() {
+            let (Some(key), Some(value)) = (key.to_str(), value.to_str()) else { continue };
+            if passthrough_matches(pattern, key) {
+                environment.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+    Ok(environment)
+}
+
+/// The variables a sphere declares or passes through, in increasing precedence:
+/// matching host variables from `env_passthrough`, the `[env]` table, then `--env` flags.
+fn sphere_environment(process: &SphereProcess, overrides: &[(String, String)]) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut environment = host_passthrough(process)?;
+    for (key, value) in process.env.iter().flatten() {
+        validate_env_name(key)?;
+        environment.insert(key.clone(), value.clone());
+    }
+    environment.extend(overrides.iter().cloned());
+    Ok(environment)
+}
+
+// --- Input Staging ---
+/// An input copied into the workspace, with a digest over every file's relative path and sha256.
+#[derive(Serialize, Clone)]
+struct StagedInput {
+    from: String,
+    to: String,
+    files: usize,
+    sha256: String,
+}
+
+/// Copies one declared input into the workspace. Inputs may only be read from the manifest
+/// directory and the `[permissions]` grants, which symlinks inside them cannot escape either.
+struct InputCopier<'a> {
+    declared: &'a str,
+    roots: &'a [PathBuf],
+    /// The canonical directories being copied, to catch symlinks that lead back into them.
+    ancestors: Vec<PathBuf>,
+    /// Each file's path relative to the input root, with its sha256.
+    hashes: Vec<(PathBuf, String)>,
+}
+
+impl InputCopier<'_> {
+    /// Copies `source` to `destination`, recursing into directories and following symlinks.
+    fn copy(&mut self, source: &Path, destination: &Path, relative: &Path) -> Result<(), Box<dyn Error>> {
+        let declared = self.declared;
+        let failed = |e: io::Error| format!("Input '{}' cannot be staged from '{}': {}", declared, source.display(), e);
+        let canonical = fs::canonicalize(source).map_err(failed)?;
+        if !self.roots.iter().any(|root| canonical.starts_with(root)) {
+            return Err(format!(
+                "Input '{}' reaches '{}', which is outside the manifest directory and every [permissions] grant.",
+                declared, canonical.display()
+            ).into());
+        }
+        if canonical.is_dir() {
+            if self.ancestors.contains(&canonical) {
+                return Err(format!("Input '{}' contains a symlink loop: '{}' leads back to '{}'.", declared, source.display(), canonical.display()).into());
+            }
+            fs::create_dir_all(destination).map_err(failed)?;
+            let mut entries: Vec<PathBuf> = fs::read_dir(source).and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect()).map_err(failed)?;
+            entries.sort();
+            self.ancestors.push(canonical);
+            for entry in entries {
+                let name = entry.file_name().unwrap_or_default();
+                self.copy(&entry, &destination.join(name), &relative.join(name))?;
+            }
+            self.ancestors.pop();
+        } else {
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(source, destination)
+                .map_err(|e| format!("Failed to copy input '{}' into the sandbox: {}", source.display(), e))?;
+            self.hashes.push((relative.to_path_buf(), sha256_file(destination)?));
+        }
+        Ok(())
+    }
+}
+
+/// Stages every declared input into `workspace`, failing on the first missing host path or on
+/// one outside the manifest directory and `grants`.
+fn stage_inputs(inputs: &[SphereInput], manifest_dir: &Path, grants: &[HostPathGrant], workspace: &Path) -> Result<Vec<StagedInput>, Box<dyn Error>> {
+    let manifest_dir = if manifest_dir.as_os_str().is_empty() { Path::new(".") } else { manifest_dir };
+    let mut roots = vec![fs::canonicalize(manifest_dir)?];
+    roots.extend(grants.iter().map(|grant| grant.path.clone()));
+    let mut staged = Vec::new();
+    for input in inputs {
+        let target = Path::new(&input.to);
+        if target.is_absolute() || target.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+            return Err(format!("Input target '{}' must be a relative path inside the workspace without '..'.", input.to).into());
+        }
+        let source = manifest_dir.join(&input.from);
+        if !source.exists() {
+            return Err(format!("Input '{}' (resolved to '{}') does not exist.", input.from, source.display()).into());
+        }
+        // `bin/` holds the dependency shims; a directory copied to the workspace root would merge into it.
+        let mut components = target.components().filter(|c| !matches!(c, std::path::Component::CurDir));
+        let shims_clobbered = match components.next() {
+            Some(first) => first.as_os_str() == "bin",
+            None => source.is_dir() && source.join("bin").exists(),
+        };
+        if shims_clobbered {
+            return Err(format!("Input target '{}' would overwrite the dependency shims in 'bin/'; stage '{}' somewhere else.", input.to, input.from).into());
+        }
+        let mut copier = InputCopier { declared: &input.from, roots: &roots, ancestors: Vec::new(), hashes: Vec::new() };
+        copier.copy(&source, &workspace.join(target), Path::new(""))?;
+        let hashes = copier.hashes;
+        let mut digest = Sha256::new();
+        for (path, hash) in &hashes {
+            digest.update(path.to_string_lossy().as_bytes());
+            digest.update(b"\0");
+            digest.update(hash.as_bytes());
+            digest.update(b"\n");
+        }
+        staged.push(StagedInput {
+            from: input.from.clone(),
+            to: input.to.clone(),
+            files: hashes.len(),
+            sha256: format!("{:x}", digest.finalize()),
+        });
+    }
+    Ok(staged)
+}
+
+// --- Output Artifacts ---
+/// A file copied out of the workspace, with its path relative to the workspace.
+struct CollectedOutput {
+    path: PathBuf,
+    sha256: String,
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn compile_output_patterns(patterns: &[String]) -> Result<Vec<glob::Pattern>, Box<dyn Error>> {
+    patterns.iter().map(|pattern| {
+        glob::Pattern::new(pattern.trim_start_matches("./"))
+            .map_err(|e| format!("Output pattern '{}' is invalid: {}", pattern, e).into())
+    }).collect()
+}
+
+/// Lists regular files under `dir`, relative to `base`, without following symlinks.
+fn workspace_files(base: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            workspace_files(base, &entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path().strip_prefix(base).unwrap_or(&entry.path()).to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Copies every workspace file matched by `patterns` (or lying under a matched directory) into
+/// `out_dir`, keeping its relative path. Symlinks are skipped so a sphere cannot export host files.
+fn collect_outputs(workspace: &Path, patterns: &[glob::Pattern], out_dir: &Path, quiet: bool) -> Result<Vec<CollectedOutput>, Box<dyn Error>> {
+    let mut files = Vec::new();
+    workspace_files(workspace, workspace, &mut files)?;
+    files.sort();
+    let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+    let mut collected = Vec::new();
+    for pattern in patterns {
+        let matched: Vec<&PathBuf> = files.iter()
+            .filter(|file| file.ancestors().any(|path| pattern.matches_path_with(path, options)))
+            .collect();
+        if matched.is_empty() && !quiet {
+            println!("   Warning: output pattern '{}' matched no files.", pattern);
+        }
+        for file in matched {
+            if collected.iter().any(|c: &CollectedOutput| &c.path == file) {
+                continue;
+            }
+            let destination = out_dir.join(file);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(workspace.join(file), &destination)
+                .map_err(|e| format!("Failed to copy output '{}' to '{}': {}", file.display(), destination.display(), e))?;
+            collected.push(CollectedOutput { path: file.clone(), sha256: sha256_file(&destination)? });
+        }
+    }
+    Ok(collected)
+}
+
+// --- Resource Limits ---
+const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// A cgroup v2 leaf created for a single run. Creation only succeeds when the caller's own
+/// cgroup is delegated (writable, with the needed controllers enabled for its children).
+struct LimitCgroup {
+    path: PathBuf,
+}
+
+impl LimitCgroup {
+    fn create(limits: &SphereLimits) -> Option<LimitCgroup> {
+        static NEXT_ID: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        if limits.memory_mb.is_none() && limits.max_processes.is_none() {
+            return None;
+        }
+        let own_cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+        let own_path = own_cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+        let path = Path::new("/sys/fs/cgroup")
+            .join(own_path.trim_start_matches('/'))
+            .join(format!("sphere-{}-{}", std::process::id(), NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed)));
+        fs::create_dir(&path).ok()?;
+        let cgroup = LimitCgroup { path };
+
+        let controllers = fs::read_to_string(cgroup.path.join("cgroup.controllers")).ok()?;
+        let controllers: Vec<&str> = controllers.split_whitespace().collect();
+        if let Some(memory_mb) = limits.memory_mb {
+            if !controllers.contains(&"memory") {
+                return None;
+            }
+            fs::write(cgroup.path.join("memory.max"), memory_mb.saturating_mul(BYTES_PER_MB).to_string()).ok()?;
+            let _ = fs::write(cgroup.path.join("memory.swap.max"), "0");
+        }
+        if let Some(max_processes) = limits.max_processes {
+            if !controllers.contains(&"pids") {
+                return None;
+            }
+            fs::write(cgroup.path.join("pids.max"), max_processes.to_string()).ok()?;
+        }
+        Some(cgroup)
+    }
+
+    /// Reads a counter such as `oom_kill` from a flat-keyed events file like `memory.events`.
+    fn event_count(&self, file: &str, key: &str) -> u64 {
+        fs::read_to_string(self.path.join(file))
+            .ok()
+            .and_then(|events| events.lines().find_map(|line| {
+                let (name, count) = line.split_once(' ')?;
+                if name == key { count.trim().parse().ok() } else { None }
+            }))
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for LimitCgroup {
+    fn drop(&mut self) {
+        // Kill anything the sphere left behind; a cgroup can only be removed once it is empty.
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        for _ in 0..50 {
+            if fs::remove_dir(&self.path).is_ok() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Installs the rlimits from `limits` on `command` and moves the child into a limit cgroup when
+/// possible. Must be called before `Sandbox::apply` so both happen before namespaces are entered.
+#[cfg(unix)]
+fn apply_resource_limits(command: &mut Command, limits: &SphereLimits) -> Result<Option<LimitCgroup>, Box<dyn Error>> {
+    use std::os::unix::process::CommandExt;
+    let to_bytes = |mb: Option<u64>| mb.map(|mb| mb.saturating_mul(BYTES_PER_MB));
+    let rlimits = [
+        ("cpu_seconds", libc::RLIMIT_CPU, limits.cpu_seconds),
+        ("memory_mb", libc::RLIMIT_AS, to_bytes(limits.memory_mb)),
+        ("max_processes", libc::RLIMIT_NPROC, limits.max_processes),
+        ("max_file_size_mb", libc::RLIMIT_FSIZE, to_bytes(limits.max_file_size_mb)),
+        ("open_files", libc::RLIMIT_NOFILE, limits.open_files),
+    ];
+    for (name, resource, value) in rlimits {
+        let Some(value) = value else { continue };
+        let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        if unsafe { libc::getrlimit(resource, &mut current) } == 0
+            && current.rlim_max != libc::RLIM_INFINITY
+            && value as libc::rlim_t > current.rlim_max
+        {
+            return Err(format!("Limit '{}' = {} exceeds this host's hard limit of {}.", name, value, current.rlim_max).into());
+        }
+    }
+
+    let cgroup = LimitCgroup::create(limits);
+    let cgroup_procs = match &cgroup {
+        Some(cgroup) => Some(std::ffi::CString::new(cgroup.path.join("cgroup.procs").to_string_lossy().into_owned())?),
+        None => None,
+    };
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(cgroup_procs) = &cgroup_procs {
+                write_control_file(cgroup_procs, b"0")?;
+            }
+            for (_, resource, value) in rlimits {
+                let Some(value) = value else { continue };
+                // The soft CPU limit delivers SIGXCPU; the hard limit a second later is a SIGKILL backstop.
+                let hard = if resource == libc::RLIMIT_CPU { value.saturating_add(1) } else { value };
+                let limit = libc::rlimit { rlim_cur: value as libc::rlim_t, rlim_max: hard as libc::rlim_t };
+                check_os(libc::setrlimit(resource, &limit))?;
+            }
+            Ok(())
+        });
+    }
+    Ok(cgroup)
+}
+
+#[cfg(not(unix))]
+fn apply_resource_limits(_command: &mut Command, _limits: &SphereLimits) -> Result<Option<LimitCgroup>, Box<dyn Error>> {
+    Err("Resource limits are only supported on Unix hosts.".into())
+}
+
+/// Total CPU time (user + system) consumed by all waited-for child processes so far.
+#[cfg(unix)]
+fn children_cpu_time() -> Duration {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) };
+    let to_duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+    to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+}
+
+#[cfg(not(unix))]
+fn children_cpu_time() -> Duration {
+    Duration::ZERO
+}
+
+/// Works out which declared limit, if any, stopped the sphere. Only a real signal death counts,
+/// never an exit code that merely looks like one. `cpu_time` is the CPU time the run consumed,
+/// needed to tell the hard CPU limit's SIGKILL from any other.
+fn describe_limit_hit(limits: &SphereLimits, status: &ExitStatus, cpu_time: Duration, cgroup: Option<&LimitCgroup>) -> Option<String> {
+    if let (Some(cgroup), Some(memory_mb)) = (cgroup, limits.memory_mb)
+        && cgroup.event_count("memory.events", "oom_kill") > 0
+    {
+        return Some(format!("memory_mb limit of {} MB (out-of-memory kill)", memory_mb));
+    }
+    if let (Some(cgroup), Some(max_processes)) = (cgroup, limits.max_processes)
+        && cgroup.event_count("pids.events", "max") > 0
+    {
+        return Some(format!("max_processes limit of {} (fork refused)", max_processes));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        let signal = status.signal();
+        if let Some(cpu_seconds) = limits.cpu_seconds
+            && (signal == Some(libc::SIGXCPU) || (signal == Some(libc::SIGKILL) && cpu_time.as_secs() >= cpu_seconds))
+        {
+            return Some(format!("cpu_seconds limit of {}s (used {:.1}s of CPU time)", cpu_seconds, cpu_time.as_secs_f64()));
+        }
+        if let (Some(max_file_size_mb), Some(libc::SIGXFSZ)) = (limits.max_file_size_mb, signal) {
+            return Some(format!("max_file_size_mb limit of {} MB (SIGXFSZ)", max_file_size_mb));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (status, cpu_time);
+    None
+}
+
+// --- Sandbox Setup (Linux namespaces) ---
+// `sphere run` gives each attempt fresh user, mount, PID, UTS and IPC namespaces, held open by a
+// keeper process, and runs the attempt's hooks and entrypoint or steps inside them. The sandbox
+// root is an empty directory populated with read-only bind mounts of the host paths a shell
+// needs, plus the sphere's workspace mounted read-write at SANDBOX_WORKSPACE.
+#[cfg(target_os = "linux")]
+const SANDBOX_WORKSPACE: &str = "/sphere";
+#[cfg(target_os = "linux")]
+const SANDBOX_HOSTNAME: &str = "sphere";
+#[cfg(target_os = "linux")]
+const SANDBOX_PATH: &str = "/sphere/bin:/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+#[cfg(target_os = "linux")]
+const SANDBOX_HOST_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/libx32"];
+#[cfg(target_os = "linux")]
+const SANDBOX_HOST_ETC: &[&str] = &[
+    "/etc/passwd", "/etc/group", "/etc/nsswitch.conf", "/etc/hosts", "/etc/resolv.conf",
+    "/etc/ld.so.cache", "/etc/ld.so.conf", "/etc/ld.so.conf.d", "/etc/alternatives",
+    "/etc/localtime", "/etc/ssl", "/etc/ca-certificates",
+];
+#[cfg(target_os = "linux")]
+const SANDBOX_DEVICES: &[&str] = &["/dev/null", "/dev/zero", "/dev/full", "/dev/random", "/dev/urandom", "/dev/tty"];
+
+#[cfg(target_os = "linux")]
+struct SandboxMount {
+    source: std::ffi::CString,
+    target: std::ffi::CString,
+    read_only: bool,
+    // Flags of the source mount that the kernel locks inside a user namespace (nosuid, nodev, ...).
+    // They must be repeated when remounting read-only or the remount is refused.
+    locked_flags: libc::c_ulong,
+}
+
+#[cfg(target_os = "linux")]
+struct SandboxSpec {
+    root: std::ffi::CString,
+    root_locked_flags: libc::c_ulong,
+    mounts: Vec<SandboxMount>,
+    proc_dir: std::ffi::CString,
+    workspace_dir: std::ffi::CString,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    network: NetworkPolicy,
+}
+
+#[cfg(target_os = "linux")]
+fn path_to_cstring(path: &Path) -> Result<std::ffi::CString, Box<dyn Error>> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| format!("Sandbox path '{}' contains a NUL byte.", path.display()).into())
+}
+
+#[cfg(target_os = "linux")]
+fn locked_mount_flags(path: &Path) -> Result<libc::c_ulong, Box<dyn Error>> {
+    let c_path = path_to_cstring(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!("Failed to inspect mount flags of '{}': {}", path.display(), io::Error::last_os_error()).into());
+    }
+    let mut flags = 0;
+    for (st_flag, ms_flag) in [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st_flag != 0 {
+            flags |= ms_flag;
+        }
+    }
+    Ok(flags)
+}
+
+/// Creates an empty mount point at `target` matching the type of `source` and records the bind mount.
+#[cfg(target_os = "linux")]
+fn add_bind_mount(mounts: &mut Vec<SandboxMount>, source: &Path, target: &Path, read_only: bool) -> Result<(), Box<dyn Error>> {
+    if source.is_dir() {
+        fs::create_dir_all(target)?;
+    } else {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::File::create(target)?;
+    }
+    mounts.push(SandboxMount {
+        source: path_to_cstring(source)?,
+        target: path_to_cstring(target)?,
+        read_only,
+        locked_flags: locked_mount_flags(source)?,
+    });
+    Ok(())
+}
+
+/// Builds the skeleton of the sandbox root on the host (mount points and symlinks) and
+/// collects everything the child needs so that no allocation happens after fork.
+#[cfg(target_os = "linux")]
+fn prepare_sandbox(workspace: &Path, scripts_dir: &Path, root: &Path, config: &SandboxConfig) -> Result<SandboxSpec, Box<dyn Error>> {
+    let mut mounts = Vec::new();
+
+    for host_dir in SANDBOX_HOST_DIRS {
+        let host_path = Path::new(host_dir);
+        let Ok(metadata) = fs::symlink_metadata(host_path) else { continue };
+        if metadata.file_type().is_symlink() {
+            // Merged-/usr systems link /bin -> usr/bin; recreate the link instead of mounting twice.
+            let link_target = fs::read_link(host_path)?;
+            std::os::unix::fs::symlink(link_target, root.join(host_path.strip_prefix("/")?))?;
+        } else if metadata.is_dir() {
+            add_bind_mount(&mut mounts, host_path, &root.join(host_path.strip_prefix("/")?), true)?;
+        }
+    }
+    for etc_entry in SANDBOX_HOST_ETC {
+        // Entries such as /etc/resolv.conf are often symlinks into /run; mount what they point to.
+        if let Ok(resolved) = fs::canonicalize(etc_entry) {
+            add_bind_mount(&mut mounts, &resolved, &root.join(etc_entry.trim_start_matches('/')), true)?;
+        }
+    }
+    for device in SANDBOX_DEVICES {
+        let device_path = Path::new(device);
+        if device_path.exists() {
+            add_bind_mount(&mut mounts, device_path, &root.join(device.trim_start_matches('/')), false)?;
+        }
+    }
+    let dev_dir = root.join("dev");
+    fs::create_dir_all(&dev_dir)?;
+    for (link, target) in [("fd", "/proc/self/fd"), ("stdin", "/proc/self/fd/0"), ("stdout", "/proc/self/fd/1"), ("stderr", "/proc/self/fd/2")] {
+        std::os::unix::fs::symlink(target, dev_dir.join(link))?;
+    }
+
+    // /tmp is a writable scratch directory inside the (otherwise read-only) sandbox root, so
+    // mount points for granted host paths under /tmp can be created ahead of time.
+    let tmp_dir = root.join("tmp");
+    fs::create_dir_all(&tmp_dir)?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&tmp_dir, fs::Permissions::from_mode(0o1777))?;
+    }
+    add_bind_mount(&mut mounts, &tmp_dir, &tmp_dir, false)?;
+    add_bind_mount(&mut mounts, workspace, &root.join(SANDBOX_WORKSPACE.trim_start_matches('/')), false)?;
+    add_bind_mount(&mut mounts, scripts_dir, &root.join(SANDBOX_SCRIPTS_DIR.trim_start_matches('/')), true)?;
+    for grant in &config.grants {
+        add_bind_mount(&mut mounts, &grant.path, &root.join(grant.path.strip_prefix("/")?), !grant.writable)?;
+    }
+
+    let proc_dir = root.join("proc");
+    fs::create_dir_all(&proc_dir)?;
+
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    Ok(SandboxSpec {
+        root: path_to_cstring(root)?,
+        root_locked_flags: locked_mount_flags(root)?,
+        mounts,
+        proc_dir: path_to_cstring(&proc_dir)?,
+        workspace_dir: path_to_cstring(Path::new(SANDBOX_WORKSPACE))?,
+        uid_map: format!("0 {} 1", uid).into_bytes(),
+        gid_map: format!("0 {} 1", gid).into_bytes(),
+        network: config.network,
+    })
+}
+
+#[cfg(unix)]
+fn check_os(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 { Err(io::Error::last_os_error()) } else { Ok(ret) }
+}
+
+#[cfg(unix)]
+fn write_control_file(path: &std::ffi::CStr, data: &[u8]) -> io::Result<()> {
+    unsafe {
+        let fd = check_os(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+        let written = libc::write(fd, data.as_ptr().cast(), data.len());
+        libc::close(fd);
+        if written != data.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn mount_os(source: Option<&std::ffi::CStr>, target: &std::ffi::CStr, fstype: Option<&std::ffi::CStr>, flags: libc::c_ulong, data: Option<&std::ffi::CStr>) -> io::Result<()> {
+    let ptr = |s: Option<&std::ffi::CStr>| s.map_or(std::ptr::null(), |s| s.as_ptr());
+    check_os(unsafe { libc::mount(ptr(source), target.as_ptr(), ptr(fstype), flags, ptr(data).cast()) })?;
+    Ok(())
+}
+
+/// Brings up the loopback interface of the current (freshly created) network namespace.
+#[cfg(target_os = "linux")]
+fn bring_up_loopback() -> io::Result<()> {
+    unsafe {
+        let sock = check_os(libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0))?;
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        let result = check_os(libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut request)).and_then(|_| {
+            request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+            check_os(libc::ioctl(sock, libc::SIOCSIFFLAGS, &request))
+        });
+        libc::close(sock);
+        result?;
+    }
+    Ok(())
+}
+
+/// Closes every descriptor of the calling process. Only uses async-signal-safe calls.
+#[cfg(target_os = "linux")]
+fn close_all_fds() {
+    unsafe {
+        if libc::syscall(libc::SYS_close_range, 0u32, u32::MAX, 0u32) != 0 {
+            for fd in 0..1024 {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+/// Reaps children until `main_pid` exits, then exits with the same status and never returns.
+/// Runs outside the sandbox's PID namespace, so a signal that killed `main_pid` is re-raised
+/// here and the caller sees it as such.
+#[cfg(target_os = "linux")]
+fn relay_exit_status(main_pid: libc::pid_t) -> ! {
+    unsafe {
+        // Termination signals sent to the process group are meant for the sphere; keep relaying
+        // its status until it exits. A SIGKILL here still tears the namespace down via PDEATHSIG.
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::signal(signal, libc::SIG_IGN);
+        }
+        // Drop every inherited descriptor so pipes to the parent close when the sphere exits.
+        close_all_fds();
+        let mut status = 0;
+        loop {
+            match libc::waitpid(-1, &mut status, 0) {
+                pid if pid == main_pid => break,
+                -1 if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted => libc::_exit(1),
+                _ => {}
+            }
+        }
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            // Re-raise the signal so the caller sees it, without dumping core into the caller's cwd.
+            let no_core = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+            libc::_exit(128 + signal);
+        }
+        libc::_exit(libc::WEXITSTATUS(status));
+    }
+}
+
+/// Keeps the sandbox's namespaces alive as its PID 1 until the keeper is killed. Processes
+/// that join the sandbox are not its children, but whatever they leave running is reparented
+/// to it when they exit; ignoring SIGCHLD has the kernel reap those without waiting.
+#[cfg(target_os = "linux")]
+fn hold_sandbox() -> ! {
+    unsafe {
+        libc::signal(libc::SIGCHLD, libc::SIG_IGN);
+        // Closing the descriptors, including the spawn error pipe, tells the parent the sandbox is ready.
+        close_all_fds();
+        loop {
+            libc::pause();
+        }
+    }
+}
+
+/// Builds the sandbox in the calling (already forked) keeper process and holds it open.
+/// Called from `pre_exec`, so it only performs raw system calls on data prepared by
+/// `prepare_sandbox`. Only returns if setting up the sandbox failed.
+#[cfg(target_os = "linux")]
+fn create_sandbox(spec: &SandboxSpec) -> io::Result<()> {
+    unsafe {
+        // The keeper must not outlive `sphere`, or the sandbox would stay around.
+        check_os(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+        let mut namespaces = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWPID | libc::CLONE_NEWUTS | libc::CLONE_NEWIPC;
+        if spec.network != NetworkPolicy::Host {
+            namespaces |= libc::CLONE_NEWNET;
+        }
+        check_os(libc::unshare(namespaces))?;
+        write_control_file(c"/proc/self/setgroups", b"deny")?;
+        write_control_file(c"/proc/self/uid_map", &spec.uid_map)?;
+        write_control_file(c"/proc/self/gid_map", &spec.gid_map)?;
+
+        // Only children of this process land in the new PID namespace, so fork once more:
+        // the child becomes PID 1, this process just relays its status.
+        match check_os(libc::fork())? {
+            0 => {}
+            init_pid => relay_exit_status(init_pid),
+        }
+        check_os(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+        mount_os(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+        mount_os(Some(&spec.root), &spec.root, None, libc::MS_BIND | libc::MS_REC, None)?;
+        for mount in &spec.mounts {
+            mount_os(Some(&mount.source), &mount.target, None, libc::MS_BIND | libc::MS_REC, None)?;
+            if mount.read_only {
+                mount_os(None, &mount.target, None, libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | mount.locked_flags, None)?;
+            }
+        }
+        mount_os(Some(c"proc"), &spec.proc_dir, Some(c"proc"), libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC, None)?;
+        check_os(libc::sethostname(SANDBOX_HOSTNAME.as_ptr().cast(), SANDBOX_HOSTNAME.len()))?;
+        if spec.network == NetworkPolicy::Loopback {
+            bring_up_loopback()?;
+        }
+
+        check_os(libc::chdir(spec.root.as_ptr()))?;
+        check_os(libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()) as libc::c_int)?;
+        check_os(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+        mount_os(None, c"/", None, libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | spec.root_locked_flags, None)?;
+        check_os(libc::chdir(spec.workspace_dir.as_ptr()))?;
+        hold_sandbox()
+    }
+}
+
+/// Moves the calling (already forked) process into the sandbox whose namespaces are open as
+/// `namespaces`, the user namespace first. Called from `pre_exec`, so it only performs raw
+/// system calls on descriptors opened by `Sandbox::apply`.
+#[cfg(target_os = "linux")]
+fn join_sandbox(namespaces: &[(std::os::fd::OwnedFd, libc::c_int)], workspace_dir: &std::ffi::CStr) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    unsafe {
+        for (namespace, kind) in namespaces {
+            check_os(libc::setns(namespace.as_raw_fd(), *kind))?;
+        }
+        check_os(libc::chdir(workspace_dir.as_ptr()))?;
+
+        // Joining a PID namespace only applies to children, so fork once more: the child runs
+        // the sphere inside the sandbox, this process relays its status from outside.
+        match check_os(libc::fork())? {
+            0 => {}
+            sphere_pid => relay_exit_status(sphere_pid),
+        }
+        check_os(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+    }
+    Ok(())
+}
+
+/// The sandbox of one attempt: fresh namespaces with `workspace` mounted read-write at
+/// SANDBOX_WORKSPACE. A keeper process holds them open, so the attempt's hooks and its
+/// entrypoint or steps all join the same sandbox; dropping it kills whatever is left inside.
+#[cfg(target_os = "linux")]
+struct Sandbox {
+    keeper: Child,
+    /// Only holds mount points; it must outlive every process in the sandbox.
+    root: tempfile::TempDir,
+    network: NetworkPolicy,
+}
+
+#[cfg(target_os = "linux")]
+impl Sandbox {
+    fn create(workspace: &Path, scripts_dir: &Path, config: &SandboxConfig) -> Result<Sandbox, Box<dyn Error>> {
+        use std::os::unix::process::CommandExt;
+        let root = tempdir()?;
+        let spec = prepare_sandbox(workspace, scripts_dir, root.path(), config)?;
+        // The keeper never gets as far as exec; spawn returns once the sandbox is set up.
+        let mut command = Command::new("/bin/true");
+        command.env_clear()
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0);
+        unsafe {
+            command.pre_exec(move || create_sandbox(&spec));
+        }
+        let keeper = command.spawn().map_err(|e| match e.raw_os_error() {
+            // unshare refuses user namespaces with these when the host disables them.
+            Some(libc::EPERM | libc::EINVAL | libc::ENOSPC) => format!("Failed to create the sandbox: {}. Unprivileged user namespaces may be disabled on this host; use '--no-sandbox' to run without isolation.", e),
+            _ => format!("Failed to create the sandbox: {}", e),
+        })?;
+        Ok(Sandbox { keeper, root, network: config.network })
+    }
+
+    fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    /// Configures `command` to run inside this sandbox, in the workspace.
+    fn apply(&self, command: &mut Command) -> Result<(), Box<dyn Error>> {
+        use std::os::unix::process::CommandExt;
+        let mut kinds = vec![
+            ("user", libc::CLONE_NEWUSER),
+            ("mnt", libc::CLONE_NEWNS),
+            ("uts", libc::CLONE_NEWUTS),
+            ("ipc", libc::CLONE_NEWIPC),
+            ("pid_for_children", libc::CLONE_NEWPID),
+        ];
+        if self.network != NetworkPolicy::Host {
+            kinds.push(("net", libc::CLONE_NEWNET));
+        }
+        let mut namespaces = Vec::new();
+        for (name, kind) in kinds {
+            let path = format!("/proc/{}/ns/{}", self.keeper.id(), name);
+            let namespace = fs::File::open(&path).map_err(|e| format!("Failed to join the sandbox: cannot open '{}': {}", path, e))?;
+            namespaces.push((std::os::fd::OwnedFd::from(namespace), kind));
+        }
+        let workspace_dir = path_to_cstring(Path::new(SANDBOX_WORKSPACE))?;
+        command.env("PATH", SANDBOX_PATH)
+            .env("HOME", Path::new(SANDBOX_WORKSPACE).join(SPHERE_HOME_DIR))
+            .env("TMPDIR", "/tmp");
+        unsafe {
+            command.pre_exec(move || join_sandbox(&namespaces, &workspace_dir));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        // The keeper's PID 1 dies with it, which kills everything left in the PID namespace.
+        let _ = self.keeper.kill();
+        let _ = self.keeper.wait();
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Sandbox;
+
+#[cfg(not(target_os = "linux"))]
+impl Sandbox {
+    fn create(_workspace: &Path, _scripts_dir: &Path, _config: &SandboxConfig) -> Result<Sandbox, Box<dyn Error>> {
+        Err("Sandboxing requires Linux namespaces, which are not available on this platform. Re-run with '--no-sandbox' to execute without isolation.".into())
+    }
+
+    fn root(&self) -> &Path {
+        Path::new("/")
+    }
+
+    fn apply(&self, _command: &mut Command) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+// --- Seccomp Syscall Filtering ---
+// Profiles are TOML or JSON documents listing syscalls and what to do when they are called.
+// The built-in "default" and "strict" profiles use the same format, so teams can copy and adapt them.
+const SECCOMP_DEFAULT_PROFILE: &str = r#"
+default_action = "allow"
+
+[[syscalls]]
+names = [
+    "ptrace", "process_vm_readv", "process_vm_writev",
+    "mount", "umount2", "pivot_root", "chroot", "move_mount", "open_tree", "fsopen", "fsconfig", "fsmount", "fspick",
+    "kexec_load", "kexec_file_load", "reboot", "init_module", "finit_module", "delete_module",
+    "swapon", "swapoff", "acct", "settimeofday", "clock_settime", "clock_adjtime", "adjtimex", "syslog",
+    "bpf", "perf_event_open", "userfaultfd", "keyctl", "add_key", "request_key",
+    "open_by_handle_at", "lookup_dcookie", "quotactl",
+]
+action = "errno"
+
+# Raw and packet sockets: socket(AF_PACKET, ...) and socket(..., SOCK_RAW, ...).
+[[syscalls]]
+names = ["socket"]
+action = "errno"
+args = [{ index = 0, value = 17 }]
+
+[[syscalls]]
+names = ["socket"]
+action = "errno"
+args = [{ index = 1, mask = 15, value = 3 }]
+"#;
+
+const SECCOMP_STRICT_EXTRA: &str = r#"
+[[syscalls]]
+names = [
+    "unshare", "setns", "personality", "name_to_handle_at", "mknodat", "vhangup",
+    "io_uring_setup", "io_uring_enter", "io_uring_register", "memfd_secret",
+    "mbind", "migrate_pages", "move_pages", "set_mempolicy",
+]
+action = "errno"
+
+# clone() that would create new namespaces (any CLONE_NEW* flag set).
+[[syscalls]]
+names = ["clone"]
+action = "errno"
+args = [{ index = 0, mask = 0x7E020000, op = "ne", value = 0 }]
+
+# clone3() passes its flags in memory, out of the filter's reach. Pretending it does not exist
+# makes libc fall back to clone(), which the rule above checks.
+[[syscalls]]
+names = ["clone3"]
+action = "enosys"
+
+# Only Unix domain sockets.
+[[syscalls]]
+names = ["socket"]
+action = "errno"
+args = [{ index = 0, op = "ne", value = 1 }]
+"#;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SeccompAction {
+    /// Let the syscall through.
+    Allow,
+    /// Let the syscall through but report it.
+    Log,
+    /// Fail the syscall with EPERM and report it.
+    Errno,
+    /// Kill the whole sphere process with SIGSYS.
+    Kill,
+    /// Fail the syscall with ENOSYS, as if the kernel lacked it, without reporting it.
+    Enosys,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SeccompArgOp {
+    #[default]
+    Eq,
+    Ne,
+}
+
+/// A condition on one syscall argument: `(arg[index] & mask) <op> value`, on the low 32 bits.
+#[derive(Deserialize, Debug, Clone)]
+struct SeccompArg {
+    index: u8,
+    value: u32,
+    #[serde(default = "seccomp_full_mask")]
+    mask: u32,
+    #[serde(default)]
+    op: SeccompArgOp,
+}
+
+fn seccomp_full_mask() -> u32 {
+    u32::MAX
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct SeccompRule {
+    names: Vec<String>,
+    action: SeccompAction,
+    #[serde(default)]
+    args: Vec<SeccompArg>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SeccompProfile {
+    default_action: SeccompAction,
+    #[serde(default)]
+    syscalls: Vec<SeccompRule>,
+}
+
+impl SeccompArg {
+    fn matches(&self, args: &[u64; 6]) -> bool {
+        let low = args[self.index as usize] as u32 & self.mask;
+        match self.op {
+            SeccompArgOp::Eq => low == self.value,
+            SeccompArgOp::Ne => low != self.value,
+        }
+    }
+}
+
+/// Loads the profile named by a manifest's `seccomp` setting: "default", "strict", or a
+/// path (relative to the manifest directory) to a `.toml` or `.json` profile file.
+fn load_seccomp_profile(setting: &str, manifest_dir: &Path) -> Result<SeccompProfile, Box<dyn Error>> {
+    let parse_builtin = |text: &str| -> Result<SeccompProfile, Box<dyn Error>> { Ok(toml::from_str(text)?) };
+    match setting {
+        "default" => parse_builtin(SECCOMP_DEFAULT_PROFILE),
+        "strict" => parse_builtin(&format!("{}{}", SECCOMP_DEFAULT_PROFILE, SECCOMP_STRICT_EXTRA)),
+        path => {
+            let profile_path = manifest_dir.join(path);
+            let content = fs::read_to_string(&profile_path)
+                .map_err(|e| format!("Failed to read seccomp profile '{}': {}", profile_path.display(), e))?;
+            let profile: SeccompProfile = if profile_path.extension().is_some_and(|ext| ext == "json") {
+                serde_json::from_str(&content).map_err(|e| format!("Failed to parse seccomp profile '{}': {}", profile_path.display(), e))?
+            } else {
+                toml::from_str(&content).map_err(|e| format!("Failed to parse seccomp profile '{}': {}", profile_path.display(), e))?
+            };
+            Ok(profile)
+        }
+    }
+}
+
+/// A syscall the filter stopped or reported while the sphere ran.
+struct SeccompViolation {
+    syscall: String,
+    pid: u32,
+    action: SeccompAction,
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp_arch {
+    macro_rules! syscall_table {
+        ($($name:ident),* $(,)?) => {
+            &[$((stringify!($name), libc::$name)),*]
+        };
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    pub const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Syscalls that profiles may name on both architectures, in the order of the generic
+    /// (aarch64) table. Entries are `SYS_`-prefixed libc constants, which follow the kernel's unistd
+    /// numbering for each architecture.
+    pub const SYSCALLS: &[(&str, libc::c_long)] = syscall_table!(
+        SYS_io_setup, SYS_io_destroy, SYS_io_submit, SYS_io_cancel, SYS_io_getevents, SYS_setxattr,
+        SYS_lsetxattr, SYS_fsetxattr, SYS_getxattr, SYS_lgetxattr, SYS_fgetxattr, SYS_listxattr,
+        SYS_llistxattr, SYS_flistxattr, SYS_removexattr, SYS_lremovexattr, SYS_fremovexattr, SYS_getcwd,
+        SYS_lookup_dcookie, SYS_eventfd2, SYS_epoll_create1, SYS_epoll_ctl, SYS_epoll_pwait, SYS_dup, SYS_dup3,
+        SYS_fcntl, SYS_inotify_init1, SYS_inotify_add_watch, SYS_inotify_rm_watch, SYS_ioctl, SYS_ioprio_set,
+        SYS_ioprio_get, SYS_flock, SYS_mknodat, SYS_mkdirat, SYS_unlinkat, SYS_symlinkat, SYS_linkat,
+        SYS_umount2, SYS_mount, SYS_pivot_root, SYS_nfsservctl, SYS_statfs, SYS_fstatfs, SYS_truncate,
+        SYS_ftruncate, SYS_fallocate, SYS_faccessat, SYS_chdir, SYS_fchdir, SYS_chroot, SYS_fchmod,
+        SYS_fchmodat, SYS_fchownat, SYS_fchown, SYS_openat, SYS_close, SYS_vhangup, SYS_pipe2, SYS_quotactl,
+        SYS_getdents64, SYS_lseek, SYS_read, SYS_write, SYS_readv, SYS_writev, SYS_pread64, SYS_pwrite64,
+        SYS_preadv, SYS_pwritev, SYS_pselect6, SYS_ppoll, SYS_signalfd4, SYS_vmsplice, SYS_splice, SYS_tee,
+        SYS_readlinkat, SYS_newfstatat, SYS_fstat, SYS_sync, SYS_fsync, SYS_fdatasync, SYS_timerfd_create,
+        SYS_timerfd_settime, SYS_timerfd_gettime, SYS_utimensat, SYS_acct, SYS_capget, SYS_capset,
+        SYS_personality, SYS_exit, SYS_exit_group, SYS_waitid, SYS_set_tid_address, SYS_unshare, SYS_futex,
+        SYS_set_robust_list, SYS_get_robust_list, SYS_nanosleep, SYS_getitimer, SYS_setitimer, SYS_kexec_load,
+        SYS_init_module, SYS_delete_module, SYS_timer_create, SYS_timer_gettime, SYS_timer_getoverrun,
+        SYS_timer_settime, SYS_timer_delete, SYS_clock_settime, SYS_clock_gettime, SYS_clock_getres,
+        SYS_clock_nanosleep, SYS_syslog, SYS_ptrace, SYS_sched_setparam, SYS_sched_setscheduler,
+        SYS_sched_getscheduler, SYS_sched_getparam, SYS_sched_setaffinity, SYS_sched_getaffinity,
+        SYS_sched_yield, SYS_sched_get_priority_max, SYS_sched_get_priority_min, SYS_sched_rr_get_interval,
+        SYS_restart_syscall, SYS_kill, SYS_tkill, SYS_tgkill, SYS_sigaltstack, SYS_rt_sigsuspend,
+        SYS_rt_sigaction, SYS_rt_sigprocmask, SYS_rt_sigpending, SYS_rt_sigtimedwait, SYS_rt_sigqueueinfo,
+        SYS_rt_sigreturn, SYS_setpriority, SYS_getpriority, SYS_reboot, SYS_setregid, SYS_setgid, SYS_setreuid,
+        SYS_setuid, SYS_setresuid, SYS_getresuid, SYS_setresgid, SYS_getresgid, SYS_setfsuid, SYS_setfsgid,
+        SYS_times, SYS_setpgid, SYS_getpgid, SYS_getsid, SYS_setsid, SYS_getgroups, SYS_setgroups, SYS_uname,
+        SYS_sethostname, SYS_setdomainname, SYS_getrusage, SYS_umask, SYS_prctl, SYS_getcpu, SYS_gettimeofday,
+        SYS_settimeofday, SYS_adjtimex, SYS_getpid, SYS_getppid, SYS_getuid, SYS_geteuid, SYS_getgid,
+        SYS_getegid, SYS_gettid, SYS_sysinfo, SYS_mq_open, SYS_mq_unlink, SYS_mq_timedsend,
+        SYS_mq_timedreceive, SYS_mq_notify, SYS_mq_getsetattr, SYS_msgget, SYS_msgctl, SYS_msgrcv, SYS_msgsnd,
+        SYS_semget, SYS_semctl, SYS_semtimedop, SYS_semop, SYS_shmget, SYS_shmctl, SYS_shmat, SYS_shmdt,
+        SYS_socket, SYS_socketpair, SYS_bind, SYS_listen, SYS_accept, SYS_connect, SYS_getsockname,
+        SYS_getpeername, SYS_sendto, SYS_recvfrom, SYS_setsockopt, SYS_getsockopt, SYS_shutdown, SYS_sendmsg,
+        SYS_recvmsg, SYS_readahead, SYS_brk, SYS_munmap, SYS_mremap, SYS_add_key, SYS_request_key, SYS_keyctl,
+        SYS_clone, SYS_execve, SYS_mmap, SYS_swapon, SYS_swapoff, SYS_mprotect, SYS_msync, SYS_mlock,
+        SYS_munlock, SYS_mlockall, SYS_munlockall, SYS_mincore, SYS_madvise, SYS_remap_file_pages, SYS_mbind,
+        SYS_get_mempolicy, SYS_set_mempolicy, SYS_migrate_pages, SYS_move_pages, SYS_rt_tgsigqueueinfo,
+        SYS_perf_event_open, SYS_accept4, SYS_recvmmsg, SYS_wait4, SYS_prlimit64, SYS_fanotify_init,
+        SYS_fanotify_mark, SYS_name_to_handle_at, SYS_open_by_handle_at, SYS_clock_adjtime, SYS_syncfs,
+        SYS_setns, SYS_sendmmsg, SYS_process_vm_readv, SYS_process_vm_writev, SYS_kcmp, SYS_finit_module,
+        SYS_sched_setattr, SYS_sched_getattr, SYS_renameat2, SYS_seccomp, SYS_getrandom, SYS_memfd_create,
+        SYS_bpf, SYS_execveat, SYS_userfaultfd, SYS_membarrier, SYS_mlock2, SYS_copy_file_range, SYS_preadv2,
+        SYS_pwritev2, SYS_pkey_mprotect, SYS_pkey_alloc, SYS_pkey_free, SYS_statx, SYS_rseq,
+        SYS_kexec_file_load, SYS_pidfd_send_signal, SYS_io_uring_setup, SYS_io_uring_enter,
+        SYS_io_uring_register, SYS_open_tree, SYS_move_mount, SYS_fsopen, SYS_fsconfig, SYS_fsmount,
+        SYS_fspick, SYS_pidfd_open, SYS_clone3, SYS_close_range, SYS_openat2, SYS_pidfd_getfd, SYS_faccessat2,
+        SYS_process_madvise, SYS_epoll_pwait2, SYS_mount_setattr, SYS_quotactl_fd, SYS_landlock_create_ruleset,
+        SYS_landlock_add_rule, SYS_landlock_restrict_self, SYS_memfd_secret, SYS_process_mrelease,
+        SYS_futex_waitv, SYS_set_mempolicy_home_node, SYS_mseal,
+    );
+    /// Syscalls only x86_64 has, mostly the older calls the generic table replaced with `*at` variants.
+    #[cfg(target_arch = "x86_64")]
+    pub const ARCH_SYSCALLS: &[(&str, libc::c_long)] = syscall_table!(
+        SYS_open, SYS_stat, SYS_lstat, SYS_poll, SYS_access, SYS_pipe, SYS_select, SYS_dup2, SYS_pause,
+        SYS_alarm, SYS_sendfile, SYS_fork, SYS_vfork, SYS_getdents, SYS_rename, SYS_mkdir, SYS_rmdir,
+        SYS_creat, SYS_link, SYS_unlink, SYS_symlink, SYS_readlink, SYS_chmod, SYS_chown, SYS_lchown,
+        SYS_getrlimit, SYS_getpgrp, SYS_utime, SYS_mknod, SYS_uselib, SYS_ustat, SYS_sysfs, SYS_modify_ldt,
+        SYS__sysctl, SYS_arch_prctl, SYS_setrlimit, SYS_iopl, SYS_ioperm, SYS_getpmsg, SYS_putpmsg,
+        SYS_afs_syscall, SYS_tuxcall, SYS_security, SYS_time, SYS_set_thread_area, SYS_get_thread_area,
+        SYS_epoll_create, SYS_epoll_ctl_old, SYS_epoll_wait_old, SYS_fadvise64, SYS_epoll_wait, SYS_utimes,
+        SYS_vserver, SYS_inotify_init, SYS_futimesat, SYS_renameat, SYS_sync_file_range, SYS_signalfd,
+        SYS_eventfd, SYS_fchmodat2,
+    );
+    #[cfg(target_arch = "aarch64")]
+    pub const ARCH_SYSCALLS: &[(&str, libc::c_long)] = &[];
+
+    // From <linux/seccomp.h>: _IOWR('!', 0, struct seccomp_notif) and _IOWR('!', 1, struct seccomp_notif_resp).
+    pub const SECCOMP_IOCTL_NOTIF_RECV: libc::c_ulong = 0xC050_2100;
+    pub const SECCOMP_IOCTL_NOTIF_SEND: libc::c_ulong = 0xC018_2101;
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn syscall_number(name: &str) -> Option<libc::c_long> {
+    seccomp_arch::SYSCALLS.iter().chain(seccomp_arch::ARCH_SYSCALLS)
+        .find(|(constant, _)| constant.strip_prefix("SYS_") == Some(name))
+        .map(|(_, number)| *number)
+}
+
+/// The syscalls `profile` names that this architecture does not have, such as 32-bit calls in a
+/// profile written for several architectures, or misspelt names.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn unknown_syscalls(profile: &SeccompProfile) -> Vec<&str> {
+    let mut unknown: Vec<&str> = Vec::new();
+    for name in profile.syscalls.iter().flat_map(|rule| &rule.names) {
+        if syscall_number(name).is_none() && !unknown.contains(&name.as_str()) {
+            unknown.push(name);
+        }
+    }
+    unknown
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn syscall_name(number: libc::c_long) -> String {
+    seccomp_arch::SYSCALLS.iter().chain(seccomp_arch::ARCH_SYSCALLS)
+        .find(|(_, candidate)| *candidate == number)
+        .map_or_else(|| format!("syscall {}", number), |(constant, _)| constant.trim_start_matches("SYS_").to_string())
+}
+
+/// A profile resolved to syscall numbers, ready to be turned into a BPF program.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+struct SeccompFilter {
+    rules: Vec<(libc::c_long, SeccompAction, Vec<SeccompArg>)>,
+    default_action: SeccompAction,
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl SeccompFilter {
+    fn compile(profile: &SeccompProfile) -> Result<SeccompFilter, Box<dyn Error>> {
+        let mut rules = Vec::new();
+        for rule in &profile.syscalls {
+            for arg in &rule.args {
+                if arg.index > 5 {
+                    return Err(format!("Seccomp rule for {:?} uses argument index {}; syscalls have arguments 0 to 5.", rule.names, arg.index).into());
+                }
+            }
+            // A syscall this architecture lacks cannot be called, so its rule has nothing to match;
+            // `unknown_syscalls` lists them for a warning.
+            for number in rule.names.iter().filter_map(|name| syscall_number(name)) {
+                rules.push((number, rule.action, rule.args.clone()));
+            }
+        }
+        Ok(SeccompFilter { rules, default_action: profile.default_action })
+    }
+
+    /// The action the profile assigns to a syscall, mirroring what the BPF program decides.
+    fn action_for(&self, number: libc::c_long, args: &[u64; 6]) -> SeccompAction {
+        self.rules.iter()
+            .find(|(rule_number, _, conditions)| *rule_number == number && conditions.iter().all(|c| c.matches(args)))
+            .map_or(self.default_action, |(_, action, _)| *action)
+    }
+
+    /// Builds the BPF program. With `notify`, `log` and `errno` defer to the supervisor through
+    /// a user notification; without it they fall back to plain allow and EPERM.
+    fn program(&self, notify: bool) -> Vec<libc::sock_filter> {
+        use libc::{BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+        let stmt = |code: u32, k: u32| libc::sock_filter { code: code as u16, jt: 0, jf: 0, k };
+        let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter { code: code as u16, jt, jf, k };
+        let ret = |action: SeccompAction| match action {
+            SeccompAction::Allow => libc::SECCOMP_RET_ALLOW,
+            SeccompAction::Log | SeccompAction::Errno if notify => libc::SECCOMP_RET_USER_NOTIF,
+            SeccompAction::Log => libc::SECCOMP_RET_ALLOW,
+            SeccompAction::Errno => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+            SeccompAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+            SeccompAction::Enosys => libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+        };
+        let load = |offset: usize| stmt(BPF_LD | BPF_W | BPF_ABS, offset as u32);
+        let nr_offset = std::mem::offset_of!(libc::seccomp_data, nr);
+        let arch_offset = std::mem::offset_of!(libc::seccomp_data, arch);
+        let args_offset = std::mem::offset_of!(libc::seccomp_data, args);
+        let low_word = if cfg!(target_endian = "little") { 0 } else { 4 };
+
+        let mut program = vec![
+            load(arch_offset),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, seccomp_arch::AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            // Refuse the x32 ABI, whose syscall numbers would bypass every rule below.
+            load(nr_offset),
+            jump(BPF_JMP | BPF_JGE | BPF_K, 0x4000_0000, 0, 1),
+            stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+        for (number, action, conditions) in &self.rules {
+            // Each rule is a self-contained block; any failed check jumps to the next block.
+            let block_len = 3 + conditions.iter().map(|c| if c.mask == u32::MAX { 2 } else { 3 }).sum::<usize>();
+            let mut block = vec![
+                load(nr_offset),
+                jump(BPF_JMP | BPF_JEQ | BPF_K, *number as u32, 0, (block_len - 2) as u8),
+            ];
+            for condition in conditions {
+                block.push(load(args_offset + 8 * condition.index as usize + low_word));
+                if condition.mask != u32::MAX {
+                    block.push(stmt(BPF_ALU | BPF_AND | BPF_K, condition.mask));
+                }
+                let skip = (block_len - block.len() - 1) as u8;
+                let (on_equal, on_different) = match condition.op {
+                    SeccompArgOp::Eq => (0, skip),
+                    SeccompArgOp::Ne => (skip, 0),
+                };
+                block.push(jump(BPF_JMP | BPF_JEQ | BPF_K, condition.value, on_equal, on_different));
+            }
+            block.push(stmt(BPF_RET | BPF_K, ret(*action)));
+            program.extend(block);
+        }
+        program.push(stmt(BPF_RET | BPF_K, ret(self.default_action)));
+        program
+    }
+}
+
+/// Passes `fd` over a Unix socket. Runs after fork, so it only uses stack memory.
+#[cfg(target_os = "linux")]
+fn send_fd(socket: libc::c_int, fd: libc::c_int) -> io::Result<()> {
+    unsafe {
+        let mut payload = [0u8; 1];
+        let mut iov = libc::iovec { iov_base: payload.as_mut_ptr().cast(), iov_len: 1 };
+        let mut control = [0u64; 8];
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<libc::c_int>() as u32) as _;
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<libc::c_int>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(header).cast::<libc::c_int>(), fd);
+        if libc::sendmsg(socket, &message, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn receive_fd(socket: libc::c_int) -> Option<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+    unsafe {
+        let mut payload = [0u8; 1];
+        let mut iov = libc::iovec { iov_base: payload.as_mut_ptr().cast(), iov_len: 1 };
+        let mut control = [0u64; 8];
+        let mut message: libc::msghdr = std::mem::zeroed();
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = std::mem::size_of_val(&control) as _;
+        if libc::recvmsg(socket, &mut message, libc::MSG_CMSG_CLOEXEC) <= 0 {
+            return None;
+        }
+        let header = libc::CMSG_FIRSTHDR(&message);
+        if header.is_null() || (*header).cmsg_type != libc::SCM_RIGHTS {
+            return None;
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<libc::c_int>());
+        Some(std::os::fd::OwnedFd::from_raw_fd(fd))
+    }
+}
+
+/// Parent-side handle on a sphere's seccomp filter. The child sends back the filter's
+/// notification descriptor, and a thread answers each `log`/`errno` notification and records it.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+struct SeccompMonitor {
+    filter: std::sync::Arc<SeccompFilter>,
+    parent_socket: Option<std::os::fd::OwnedFd>,
+    child_socket: Option<std::os::fd::OwnedFd>,
+    worker: Option<(std::sync::Arc<std::sync::atomic::AtomicBool>, std::thread::JoinHandle<Vec<SeccompViolation>>)>,
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl SeccompMonitor {
+    /// Starts answering notifications. Call before spawning the child: the filter is already
+    /// active when the child execs the entrypoint, so that exec may itself need an answer.
+    fn start(&mut self, quiet: bool) {
+        use std::os::fd::AsRawFd;
+        let Some(parent_socket) = self.parent_socket.take() else { return };
+        let filter = std::sync::Arc::clone(&self.filter);
+        let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let stop_flag = std::sync::Arc::clone(&stop);
+        let worker = std::thread::spawn(move || {
+            let mut violations = Vec::new();
+            let Some(listener) = receive_fd(parent_socket.as_raw_fd()) else { return violations };
+            while !stop_flag.load(std::sync::atomic::Ordering::Relaxed) {
+                let mut poll_fd = libc::pollfd { fd: listener.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+                if unsafe { libc::poll(&mut poll_fd, 1, 100) } <= 0 {
+                    continue;
+                }
+                if poll_fd.revents & libc::POLLIN == 0 {
+                    // POLLHUP: every process using the filter has exited.
+                    break;
+                }
+                let mut notification: libc::seccomp_notif = unsafe { std::mem::zeroed() };
+                if unsafe { libc::ioctl(listener.as_raw_fd(), seccomp_arch::SECCOMP_IOCTL_NOTIF_RECV, &mut notification) } != 0 {
+                    continue;
+                }
+                let number = notification.data.nr as libc::c_long;
+                let action = filter.action_for(number, &notification.data.args);
+                let mut response: libc::seccomp_notif_resp = unsafe { std::mem::zeroed() };
+                response.id = notification.id;
+                if action == SeccompAction::Log {
+                    response.flags = libc::SECCOMP_USER_NOTIF_FLAG_CONTINUE as u32;
+                } else {
+                    response.error = -libc::EPERM;
+                }
+                unsafe { libc::ioctl(listener.as_raw_fd(), seccomp_arch::SECCOMP_IOCTL_NOTIF_SEND, &response) };
+
+                let violation = SeccompViolation { syscall: syscall_name(number), pid: notification.pid, action };
+                if !quiet {
+                    let verb = if action == SeccompAction::Log { "logged" } else { "blocked" };
+                    eprintln!("-> seccomp: {} {} (pid {})", verb, violation.syscall, violation.pid);
+                }
+                violations.push(violation);
+            }
+            violations
+        });
+        self.worker = Some((stop, worker));
+    }
+
+    /// Stops the notification thread and returns what it recorded.
+    fn finish(&mut self) -> Vec<SeccompViolation> {
+        // Closing our copy of the child's end unblocks recvmsg if the child never sent a descriptor.
+        self.child_socket = None;
+        match self.worker.take() {
+            Some((stop, worker)) => {
+                stop.store(true, std::sync::atomic::Ordering::Relaxed);
+                worker.join().unwrap_or_default()
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Installs the sphere's seccomp filter as the last step before exec. Must be called after
+/// `Sandbox::apply` so that joining the sandbox's namespaces happens before the filter exists.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn apply_seccomp(command: &mut Command, profile: &SeccompProfile) -> Result<SeccompMonitor, Box<dyn Error>> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::process::CommandExt;
+
+    let filter = SeccompFilter::compile(profile)?;
+    let notify_program = filter.program(true);
+    let fallback_program = filter.program(false);
+    if notify_program.len() > u16::MAX as usize {
+        return Err("Seccomp profile is too large to compile into a filter.".into());
+    }
+
+    let mut sockets = [0; 2];
+    check_os(unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0, sockets.as_mut_ptr()) })?;
+    let (parent_socket, child_socket) = unsafe { (OwnedFd::from_raw_fd(sockets[0]), OwnedFd::from_raw_fd(sockets[1])) };
+    let child_socket_fd = child_socket.as_raw_fd();
+
+    unsafe {
+        command.pre_exec(move || {
+            check_os(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+            let install = |program: &[libc::sock_filter], flags: libc::c_ulong| {
+                let fprog = libc::sock_fprog { len: program.len() as u16, filter: program.as_ptr() as *mut _ };
+                libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, flags, &fprog)
+            };
+            let listener = install(&notify_program, libc::SECCOMP_FILTER_FLAG_NEW_LISTENER);
+            if listener >= 0 {
+                let listener = listener as libc::c_int;
+                let sent = send_fd(child_socket_fd, listener);
+                libc::close(listener);
+                sent?;
+            } else {
+                // Kernels without user notifications (before 5.0) still get the filter, minus the reporting.
+                check_os(install(&fallback_program, 0) as libc::c_int)?;
+            }
+            Ok(())
+        });
+    }
+    Ok(SeccompMonitor {
+        filter: std::sync::Arc::new(filter),
+        parent_socket: Some(parent_socket),
+        child_socket: Some(child_socket),
+        worker: None,
+    })
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+struct SeccompMonitor;
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+impl SeccompMonitor {
+    fn start(&mut self, _quiet: bool) {}
+
+    fn finish(&mut self) -> Vec<SeccompViolation> {
+        Vec::new()
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+fn apply_seccomp(_command: &mut Command, _profile: &SeccompProfile) -> Result<SeccompMonitor, Box<dyn Error>> {
+    Err("Seccomp filtering is only supported on Linux (x86_64 and aarch64). Remove the 'seccomp' setting to run on this host.".into())
+}
+
+const SECCOMP_SUPPORTED: bool = cfg!(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")));
+
+/// Prints what the seccomp filter did during a run, grouped by syscall.
+fn report_seccomp_violations(profile: &str, violations: &[SeccompViolation], status: &ExitStatus) {
+    if !violations.is_empty() {
+        let summary: Vec<String> = count_seccomp_violations(violations).iter()
+            .map(|count| format!("{} {} x{}", count.action, count.syscall, count.count))
+            .collect();
+        eprintln!("-> Seccomp profile '{}' intercepted {} syscall(s): {}", profile, violations.len(), summary.join(", "));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if status.signal() == Some(libc::SIGSYS) {
+            eprintln!("-> Sphere was killed by seccomp profile '{}' (SIGSYS).", profile);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = status;
+}
+
+/// Groups `violations` by syscall and what the filter did, in the order they were first seen.
+fn count_seccomp_violations(violations: &[SeccompViolation]) -> Vec<SeccompViolationReport> {
+    let mut counts: Vec<SeccompViolationReport> = Vec::new();
+    for violation in violations {
+        let action = if violation.action == SeccompAction::Log { "logged" } else { "blocked" };
+        match counts.iter_mut().find(|count| count.syscall == violation.syscall && count.action == action) {
+            Some(count) => count.count += 1,
+            None => counts.push(SeccompViolationReport { syscall: violation.syscall.clone(), action, count: 1 }),
+        }
+    }
+    counts
+}
+
+// --- Process Supervision ---
+// The entrypoint runs as the leader of its own process group so a timeout (or a Ctrl-C
+// forwarded from the terminal) can reach everything it started, including dependency shims.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// The least time `on_failure` and `teardown` get, even once a timed-out run has none left.
+const CLEANUP_HOOK_GRACE: Duration = Duration::from_secs(10);
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The last forwarded signal received, and how many have been received. Every supervisor
+/// (one per concurrently running step) forwards each new signal to its own process group.
+static PENDING_SIGNAL: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+static SIGNAL_GENERATION: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(unix)]
+extern "C" fn record_pending_signal(signal: libc::c_int) {
+    PENDING_SIGNAL.store(signal, std::sync::atomic::Ordering::Relaxed);
+    SIGNAL_GENERATION.fetch_add(1, std::sync::atomic::Ordering::Release);
+}
+
+fn signal_generation() -> usize {
+    SIGNAL_GENERATION.load(std::sync::atomic::Ordering::Acquire)
+}
+
+/// Catches SIGINT, SIGTERM and SIGHUP so `supervise_child` can forward them to the sphere's
+/// process group, which runs separately from ours.
+fn install_signal_forwarding() {
+    #[cfg(unix)]
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+        unsafe { libc::signal(signal, record_pending_signal as *const () as libc::sighandler_t) };
+    }
+}
+
+fn signal_process_group(child: &Child, signal: i32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::killpg(child.id() as libc::pid_t, signal);
+    }
+    #[cfg(not(unix))]
+    let _ = (child, signal);
+}
+
+/// Hands the controlling terminal to the sphere's process group while it runs, so an entrypoint
+/// reading stdin is not stopped with SIGTTIN. Dropping it takes the terminal back.
+struct TerminalForeground {
+    #[cfg(unix)]
+    pgrp: libc::pid_t,
+}
+
+impl TerminalForeground {
+    /// Arranges for the child to become the terminal's foreground process group before exec.
+    /// Returns `None` when stdin is not a terminal or `sphere` itself is not in the foreground.
+    /// Must be registered before `Sandbox::apply`, whose PID namespace hides the process group.
+    fn hand_over(command: &mut Command) -> Option<Self> {
+        #[cfg(unix)]
+        unsafe {
+            use std::os::unix::process::CommandExt;
+            let pgrp = libc::getpgrp();
+            if libc::isatty(libc::STDIN_FILENO) != 1 || libc::tcgetpgrp(libc::STDIN_FILENO) != pgrp {
+                return None;
+            }
+            command.pre_exec(|| {
+                set_terminal_foreground(libc::getpgrp());
+                Ok(())
+            });
+            Some(TerminalForeground { pgrp })
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = command;
+            None
+        }
+    }
+}
+
+impl Drop for TerminalForeground {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        set_terminal_foreground(self.pgrp);
+    }
+}
+
+/// `tcsetpgrp` on stdin with SIGTTOU ignored, as a background process group may not otherwise
+/// change the foreground group. Only uses async-signal-safe calls.
+#[cfg(unix)]
+fn set_terminal_foreground(pgrp: libc::pid_t) {
+    unsafe {
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgrp);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+/// The shell-style exit code for `status`: the child's own code, or 128 + signal number
+/// when it was killed by a signal.
+fn exit_code_of(status: &ExitStatus) -> i32 {
+    match signal_of(status) {
+        Some(signal) => 128 + signal,
+        None => status.code().unwrap_or(1),
+    }
+}
+
+/// The signal that killed the process, if one did.
+fn signal_of(status: &ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// Waits for `child`, sending SIGTERM to its process group once `deadline` passes and SIGKILL
+/// after a grace period. Returns the exit status and whether the deadline was hit.
+fn supervise_child(child: &mut Child, deadline: Option<Instant>) -> io::Result<(ExitStatus, bool)> {
+    #[cfg(unix)]
+    let (sigterm, sigkill) = (libc::SIGTERM, libc::SIGKILL);
+    #[cfg(not(unix))]
+    let (sigterm, sigkill) = (15, 9);
+
+    let mut seen_generation = signal_generation();
+    let mut timed_out = false;
+    let mut kill_deadline: Option<Instant> = None;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if timed_out {
+                // Sweep up anything that outlived the group leader.
+                signal_process_group(child, sigkill);
+            }
+            return Ok((status, timed_out));
+        }
+        let now = Instant::now();
+        let generation = signal_generation();
+        if generation != seen_generation {
+            seen_generation = generation;
+            signal_process_group(child, PENDING_SIGNAL.load(std::sync::atomic::Ordering::Relaxed));
+            kill_deadline.get_or_insert(now + KILL_GRACE_PERIOD);
+        }
+        if !timed_out && deadline.is_some_and(|deadline| now >= deadline) {
+            timed_out = true;
+            signal_process_group(child, sigterm);
+            kill_deadline.get_or_insert(now + KILL_GRACE_PERIOD);
+        }
+        if kill_deadline.is_some_and(|deadline| now >= deadline) {
+            signal_process_group(child, sigkill);
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL);
+    }
+}
+
+/// How long to keep reading a process's pipes once it has exited. Background processes it
+/// started may hold them open until the attempt ends; their later output is not waited for.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// A child's pipe being read on a background thread.
+struct PipeReader {
+    captured: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl PipeReader {
+    /// Starts `copy` on a thread, handing it the pipe and the buffer to append what it read to.
+    fn spawn<R: Send + 'static>(pipe: Option<R>, copy: impl FnOnce(R, &std::sync::Mutex<Vec<u8>>) + Send + 'static) -> PipeReader {
+        let captured = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let buffer = captured.clone();
+        let thread = std::thread::spawn(move || {
+            if let Some(pipe) = pipe {
+                copy(pipe, &buffer);
+            }
+        });
+        PipeReader { captured, thread }
+    }
+
+    /// Waits for the end of the pipe, or until `give_up` while something else still holds it
+    /// open, and returns what was read.
+    fn finish(self, give_up: Instant) -> Vec<u8> {
+        while !self.thread.is_finished() && Instant::now() < give_up {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        std::mem::take(&mut *self.captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+    }
+}
+
+/// Reads a child's pipe to the end on a background thread.
+fn collect_pipe<R: io::Read + Send + 'static>(pipe: Option<R>) -> PipeReader {
+    PipeReader::spawn(pipe, |mut pipe, captured| {
+        let mut chunk = [0u8; 8192];
+        while let Ok(n) = pipe.read(&mut chunk) && n > 0 {
+            captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).extend_from_slice(&chunk[..n]);
+        }
+    })
+}
+
+/// Copies a child's pipe unchanged to our stdout (or stderr) as it arrives, and keeps a copy.
+fn tee_pipe<R: io::Read + Send + 'static>(pipe: Option<R>, to_stderr: bool) -> PipeReader {
+    PipeReader::spawn(pipe, move |mut pipe, captured| {
+        let mut chunk = [0u8; 8192];
+        while let Ok(n) = pipe.read(&mut chunk) && n > 0 {
+            let _ = if to_stderr {
+                io::stderr().lock().write_all(&chunk[..n])
+            } else {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&chunk[..n]).and_then(|()| stdout.flush())
+            };
+            captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).extend_from_slice(&chunk[..n]);
+        }
+    })
+}
+
+/// Copies a child's pipe to our stdout line by line, each line prefixed with `label`, and
+/// keeps what was read. Both streams share the stdout lock, so lines interleave in arrival
+/// order without tearing.
+fn prefix_pipe<R: io::Read + Send + 'static>(pipe: Option<R>, label: String) -> PipeReader {
+    PipeReader::spawn(pipe, move |pipe, captured| {
+        let mut reader = io::BufReader::new(pipe);
+        let mut line = Vec::new();
+        while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+            let mut stdout = io::stdout().lock();
+            let _ = stdout.write_all(label.as_bytes());
+            let _ = stdout.write_all(&line);
+            if !line.ends_with(b"\n") {
+                let _ = stdout.write_all(b"\n");
+            }
+            let _ = stdout.flush();
+            captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).append(&mut line);
+        }
+    })
+}
+
+// --- Launching Sandboxed Processes ---
+/// Settings shared by every process an attempt starts: its entrypoint or each step of a pipeline,
+/// and its hooks. They all run in the attempt's sandbox, each with its own limits and seccomp filter.
+struct RunContext<'a> {
+    options: &'a RunOptions,
+    quiet: bool,
+    workspace: PathBuf,
+    bin_path: PathBuf,
+    /// Where interpreter scripts are written, outside the workspace.
+    scripts_dir: PathBuf,
+    sandbox_config: &'a SandboxConfig,
+    /// The attempt's sandbox, unless `--no-sandbox` was given.
+    sandbox: Option<Sandbox>,
+    limits: SphereLimits,
+    limits_declared: bool,
+    seccomp_setting: Option<&'a str>,
+    seccomp_profile: Option<&'a SeccompProfile>,
+    timeout: Option<Duration>,
+    /// When `timeout` runs out. It covers the whole attempt: every step and hook shares it.
+    deadline: Option<Instant>,
+    success_codes: Vec<i32>,
+    /// Set once a process has printed how it is confined; the details are the same for every one.
+    announced: std::sync::atomic::AtomicBool,
+    /// Every process launched so far, for the run report.
+    processes: std::sync::Mutex<Vec<ProcessReport>>,
+    /// The limit cgroups of finished processes, kept until the attempt ends so that anything
+    /// they left running in the sandbox stays within its limits.
+    limit_cgroups: std::sync::Mutex<Vec<LimitCgroup>>,
+}
+
+/// Which of a run's processes is being launched, for the messages printed about it.
+#[derive(Clone, Copy)]
+enum LaunchRole<'a> {
+    Entrypoint,
+    Step(&'a str),
+    Hook(&'a str),
+}
+
+impl LaunchRole<'_> {
+    /// The role and name recorded in the run report.
+    fn describe(&self) -> (&'static str, Option<String>) {
+        match self {
+            LaunchRole::Entrypoint => ("entrypoint", None),
+            LaunchRole::Step(name) => ("step", Some(name.to_string())),
+            LaunchRole::Hook(name) => ("hook", Some(name.to_string())),
+        }
+    }
+
+    fn subject(&self) -> String {
+        match self {
+            LaunchRole::Entrypoint => "Sphere".to_string(),
+            LaunchRole::Step(name) => format!("Step '{}'", name),
+            LaunchRole::Hook(name) => format!("Hook '{}'", name),
+        }
+    }
+}
+
+/// How a launched process ended. `output` only holds data when it was not streamed straight
+/// through to the terminal.
+struct LaunchOutcome {
+    output: Output,
+    exit_code: i32,
+    timed_out: bool,
+}
+
+impl RunContext<'_> {
+    fn shell(&self) -> &'static str {
+        if self.options.no_sandbox { "sh" } else { "/bin/sh" }
+    }
+
+    /// The scripts directory as the sphere sees it.
+    fn scripts_dir_inside(&self) -> PathBuf {
+        if self.options.no_sandbox { self.scripts_dir.clone() } else { PathBuf::from(SANDBOX_SCRIPTS_DIR) }
+    }
+
+    /// Runs `argv` and waits for it. `interactive` connects this terminal's stdin.
+    fn launch(&self, argv: &[String], environment: &BTreeMap<String, String>, role: LaunchRole, interactive: bool) -> Result<LaunchOutcome, Box<dyn Error>> {
+        let options = self.options;
+        let quiet = self.quiet;
+        let announce = !quiet && !self.announced.swap(true, std::sync::atomic::Ordering::Relaxed);
+        let limits = &self.limits;
+        let sandbox_config = self.sandbox_config;
+
+        let mut command = Command::new(&argv[0]);
+        command.env_clear();
+        let terminal = if interactive { TerminalForeground::hand_over(&mut command) } else { None };
+        let limit_cgroup = apply_resource_limits(&mut command, limits)?;
+        if announce && self.limits_declared {
+            let declared: Vec<String> = [
+                ("cpu_seconds", limits.cpu_seconds),
+                ("memory_mb", limits.memory_mb),
+                ("max_processes", limits.max_processes),
+                ("max_file_size_mb", limits.max_file_size_mb),
+                ("open_files", limits.open_files),
+            ].iter().filter_map(|(name, value)| value.map(|v| format!("{}={}", name, v))).collect();
+            println!("-> Applying resource limits: {} (enforced via {})", declared.join(", "), if limit_cgroup.is_some() { "rlimits and cgroup v2" } else { "rlimits" });
+        }
+        if options.no_sandbox {
+            let original_path = std::env::var("PATH").unwrap_or_default();
+            let new_path = format!("{}:{}", self.bin_path.to_string_lossy(), original_path);
+            let scratch_dir = self.workspace.join("tmp");
+            fs::create_dir_all(&scratch_dir)?;
+            command.current_dir(&self.workspace)
+                .env("PATH", new_path)
+                .env("HOME", self.workspace.join(SPHERE_HOME_DIR))
+                .env("TMPDIR", scratch_dir);
+            if announce {
+                println!("-> WARNING: --no-sandbox given, the entrypoint can see and modify the host filesystem.");
+                if !sandbox_config.grants.is_empty() {
+                    println!("   [permissions] are not enforced without the sandbox.");
+                }
+                if sandbox_config.network != NetworkPolicy::Host {
+                    println!("   network = \"{}\" is not enforced without the sandbox.", sandbox_config.network.as_str());
+                }
+            }
+        } else if let Some(sandbox) = &self.sandbox {
+            sandbox.apply(&mut command)?;
+            if announce {
+                println!("-> Isolated in user, mount, PID, UTS and IPC namespaces (root: {:?})", sandbox.root());
+                println!("   Network policy: {}", sandbox_config.network.as_str());
+            }
+        }
+        let mut seccomp_monitor = match self.seccomp_profile {
+            Some(profile) => Some(apply_seccomp(&mut command, profile)?),
+            None => None,
+        };
+        if announce && let Some(setting) = self.seccomp_setting {
+            println!("-> Seccomp profile: {}", setting);
+        }
+        command.envs(environment);
+        if announce && !environment.is_empty() {
+            println!("-> Environment: {}", environment.keys().cloned().collect::<Vec<_>>().join(", "));
+        }
+        command.args(&argv[1..]);
+        command.stdin(if interactive { Stdio::inherit() } else { Stdio::null() });
+        // With a JSON report on stdout the output modes do not apply: the output is only captured.
+        let output_mode = if options.format == ReportFormat::Json { OutputMode::Framed } else { options.output_mode };
+        if output_mode == OutputMode::Stream && !options.captures_output() {
+            command.stdout(Stdio::inherit()).stderr(Stdio::inherit());
+        } else {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+
+        if !quiet {
+            match role {
+                LaunchRole::Entrypoint => println!("-> Executing entrypoint inside sandbox..."),
+                LaunchRole::Step(name) => println!("-> Starting step '{}'...", name),
+                LaunchRole::Hook(name) => println!("-> Running {} hook...", name),
+            }
+            if announce && let Some(timeout) = self.timeout {
+                println!("   Timeout: {:?}", timeout);
+            }
+        }
+        install_signal_forwarding();
+        let cpu_time_before = children_cpu_time();
+        if let Some(monitor) = seccomp_monitor.as_mut() {
+            monitor.start(quiet);
+        }
+        let started_at = std::time::SystemTime::now();
+        let mut child = command.spawn().map_err(|e| {
+            let subject = match role {
+                LaunchRole::Entrypoint => "the entrypoint".to_string(),
+                LaunchRole::Step(name) => format!("step '{}'", name),
+                LaunchRole::Hook(name) => format!("{} hook", name),
+            };
+            format!("Failed to start {}: cannot run '{}': {}", subject, argv[0], e)
+        })?;
+        let label = match role {
+            LaunchRole::Entrypoint => String::new(),
+            LaunchRole::Step(name) | LaunchRole::Hook(name) => format!("{} ", name),
+        };
+        let (stdout_reader, stderr_reader) = match output_mode {
+            OutputMode::Stream => (tee_pipe(child.stdout.take(), false), tee_pipe(child.stderr.take(), true)),
+            OutputMode::Prefixed => (prefix_pipe(child.stdout.take(), format!("{}stdout | ", label)), prefix_pipe(child.stderr.take(), format!("{}stderr | ", label))),
+            OutputMode::Framed => (collect_pipe(child.stdout.take()), collect_pipe(child.stderr.take())),
+        };
+        let (status, timed_out) = supervise_child(&mut child, self.deadline_for(role))?;
+        drop(terminal);
+        let seccomp_violations = seccomp_monitor.as_mut().map(SeccompMonitor::finish).unwrap_or_default();
+        let drained_by = Instant::now() + OUTPUT_DRAIN_TIMEOUT;
+        let output = Output {
+            status,
+            stdout: stdout_reader.finish(drained_by),
+            stderr: stderr_reader.finish(drained_by),
+        };
+        if !quiet && matches!(role, LaunchRole::Entrypoint) {
+            println!("-> Execution finished.\n");
+        }
+        let cpu_time = children_cpu_time().saturating_sub(cpu_time_before);
+        if let Some(limit) = describe_limit_hit(limits, &output.status, cpu_time, limit_cgroup.as_ref()) {
+            eprintln!("-> {} was stopped by its {}.", role.subject(), limit);
+        }
+        report_seccomp_violations(self.seccomp_setting.unwrap_or("none"), &seccomp_violations, &output.status);
+        let captured = |bytes: &[u8]| options.captures_output().then(|| String::from_utf8_lossy(bytes).into_owned());
+        let (kind, name) = role.describe();
+        self.processes.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(ProcessReport {
+            role: kind,
+            name,
+            started_at: format_timestamp(started_at),
+            ended_at: format_timestamp(std::time::SystemTime::now()),
+            exit_code: exit_code_of(&output.status),
+            signal: signal_of(&output.status),
+            timed_out,
+            seccomp_violations: count_seccomp_violations(&seccomp_violations),
+            stdout: captured(&output.stdout),
+            stderr: captured(&output.stderr),
+        });
+        if let Some(cgroup) = limit_cgroup {
+            self.limit_cgroups.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(cgroup);
+        }
+        Ok(LaunchOutcome { exit_code: exit_code_of(&output.status), output, timed_out })
+    }
+
+    /// The deadline a process must finish by: the attempt's, except that the `on_failure` and
+    /// `teardown` hooks always get at least `CLEANUP_HOOK_GRACE`, so they can clean up after a timeout.
+    fn deadline_for(&self, role: LaunchRole) -> Option<Instant> {
+        let deadline = self.deadline?;
+        match role {
+            LaunchRole::Hook("on_failure" | "teardown") => Some(deadline.max(Instant::now() + CLEANUP_HOOK_GRACE)),
+            _ => Some(deadline),
+        }
+    }
+
+    /// Prints buffered output in framed mode; in the other modes it was shown as it arrived.
+    fn show_output(&self, outcome: &LaunchOutcome, role: LaunchRole) {
+        if self.options.output_mode != OutputMode::Framed || self.options.format == ReportFormat::Json {
+            return;
+        }
+        if !self.quiet {
+            match role {
+                LaunchRole::Entrypoint => {}
+                LaunchRole::Step(name) => println!("-> Output of step '{}':", name),
+                LaunchRole::Hook(name) => println!("-> Output of the {} hook:", name),
+            }
+        }
+        print_framed_output(&outcome.output, self.quiet);
+    }
+
+    /// Turns a timeout or an exit code outside `success_codes` into the error `sphere` exits with.
+    /// Hooks only succeed with exit code 0.
+    fn check_outcome(&self, outcome: &LaunchOutcome, role: LaunchRole) -> Result<(), Box<dyn Error>> {
+        let subject = role.subject();
+        let success_codes: &[i32] = if matches!(role, LaunchRole::Hook(_)) { &[0] } else { &self.success_codes };
+        if outcome.timed_out {
+            return Err(Box::new(ExitCodeError {
+                code: TIMEOUT_EXIT_CODE,
+                message: format!("{} timed out: the run's {:?} timeout expired, so its process group was terminated.", subject, self.timeout.unwrap_or_default()),
+            }));
+        }
+        if !success_codes.contains(&outcome.exit_code) {
+            return Err(Box::new(ExitCodeError {
+                code: outcome.exit_code,
+                message: format!("{} exited with status {}.", subject, outcome.exit_code),
+            }));
+        }
+        if outcome.exit_code != 0 && !self.quiet {
+            println!("-> Exit code {} is listed in success_codes; treating the run as successful.", outcome.exit_code);
+        }
+        Ok(())
+    }
+
+    /// Runs the entrypoint, passing it everything after `--`.
+    fn run_entrypoint(&self, process: &SphereProcess, entrypoint: &Entrypoint, environment: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+        let name = process.id.as_deref().unwrap_or("sphere");
+        let mut argv = command_line(process, entrypoint, name, ENTRYPOINT_SCRIPT, &self.scripts_dir, &self.scripts_dir_inside(), self.shell())?;
+        // Everything after `--` becomes "$@" (or plain trailing arguments for argv entrypoints).
+        argv.extend(self.options.args.iter().cloned());
+        let outcome = self.launch(&argv, environment, LaunchRole::Entrypoint, !self.options.no_stdin)?;
+        self.show_output(&outcome, LaunchRole::Entrypoint);
+        self.check_outcome(&outcome, LaunchRole::Entrypoint)
+    }
+
+    /// Runs the `setup`, `teardown` or `on_failure` hook. Hooks get no arguments and no stdin.
+    fn run_hook(&self, process: &SphereProcess, name: &str, hook: &Entrypoint, environment: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+        let argv = command_line(process, hook, name, name, &self.scripts_dir, &self.scripts_dir_inside(), self.shell())?;
+        let outcome = self.launch(&argv, environment, LaunchRole::Hook(name), false)?;
+        self.show_output(&outcome, LaunchRole::Hook(name));
+        self.check_outcome(&outcome, LaunchRole::Hook(name))
+    }
+
+    /// Copies the workspace files matching `patterns` to `--out-dir` and lists them.
+    fn collect(&self, patterns: &[glob::Pattern]) -> Result<(), Box<dyn Error>> {
+        if patterns.is_empty() {
+            return Ok(());
+        }
+        let out_dir = self.options.out_dir.as_deref().unwrap_or(Path::new("."));
+        let collected = collect_outputs(&self.workspace, patterns, out_dir, self.quiet)?;
+        if !self.quiet {
+            println!("-> Collected {} output file(s) into {}:", collected.len(), out_dir.display());
+            for output in &collected {
+                println!("   {}  sha256:{}", output.path.display(), output.sha256);
+            }
+        }
+        Ok(())
+    }
+}
+
+// --- Pipelines ---
+/// Orders the steps so each one comes after everything it `needs`. With `target`, only that step
+/// and the steps it transitively needs are included.
+fn plan_steps(steps: &[SphereStep], target: Option<&str>) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut index_of = HashMap::new();
+    for (index, step) in steps.iter().enumerate() {
+        if index_of.insert(step.name.as_str(), index).is_some() {
+            return Err(format!("Step '{}' is defined more than once.", step.name).into());
+        }
+    }
+    for step in steps {
+        if let Some(need) = step.needs.iter().find(|need| !index_of.contains_key(need.as_str())) {
+            return Err(format!("Step '{}' needs '{}', which is not a step of this Sphere.", step.name, need).into());
+        }
+    }
+    let roots = match target {
+        Some(name) => vec![*index_of.get(name).ok_or_else(|| {
+            let names: Vec<&str> = steps.iter().map(|step| step.name.as_str()).collect();
+            format!("Step '{}' does not exist; the steps are: {}.", name, names.join(", "))
+        })?],
+        None => (0..steps.len()).collect(),
+    };
+
+    fn visit(index: usize, steps: &[SphereStep], index_of: &HashMap<&str, usize>, chain: &mut Vec<usize>, order: &mut Vec<usize>) -> Result<(), Box<dyn Error>> {
+        if order.contains(&index) {
+            return Ok(());
+        }
+        if let Some(start) = chain.iter().position(|&i| i == index) {
+            let cycle: Vec<&str> = chain[start..].iter().chain([&index]).map(|&i| steps[i].name.as_str()).collect();
+            return Err(format!("Pipeline steps form a cycle: {}", cycle.join(" -> ")).into());
+        }
+        chain.push(index);
+        for need in &steps[index].needs {
+            visit(index_of[need.as_str()], steps, index_of, chain, order)?;
+        }
+        chain.pop();
+        order.push(index);
+        Ok(())
+    }
+    let mut order = Vec::new();
+    for root in roots {
+        visit(root, steps, &index_of, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+/// A step with its command line, environment and output patterns worked out before anything runs.
+struct PreparedStep<'a> {
+    step: &'a SphereStep,
+    argv: Vec<String>,
+    environment: BTreeMap<String, String>,
+    output_patterns: Vec<glob::Pattern>,
+}
+
+enum StepStatus {
+    Passed,
+    Failed(i32),
+    TimedOut,
+    NotStarted,
+    Skipped,
+}
+
+/// Runs a `[[steps]]` pipeline in dependency order, up to `--jobs` steps at a time. Once a step
+/// fails (or we are asked to stop) no further steps start; the first failure is returned after
+/// the running ones finish.
+fn run_pipeline(context: &RunContext, process: &SphereProcess, environment: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+    let steps = process.steps.as_deref().unwrap_or_default();
+    let order = plan_steps(steps, context.options.step.as_deref())?;
+    let mut prepared = Vec::new();
+    for &index in &order {
+        let step = &steps[index];
+        let script_file = format!("step-{}-{}", index, step.name.replace(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-', "_"));
+        let mut argv = command_line(process, &step.run, &step.name, &script_file, &context.scripts_dir, &context.scripts_dir_inside(), context.shell())?;
+        argv.extend(context.options.args.iter().cloned());
+        let mut step_environment = environment.clone();
+        for (key, value) in step.env.iter().flatten() {
+            validate_env_name(key)?;
+            step_environment.insert(key.clone(), value.clone());
+        }
+        // `--env` still wins over a step's own `env`.
+        step_environment.extend(context.options.env.iter().cloned());
+        let output_patterns = compile_output_patterns(step.outputs.as_deref().unwrap_or_default())?;
+        prepared.push(PreparedStep { step, argv, environment: step_environment, output_patterns });
+    }
+    let jobs = context.options.jobs.get();
+    if !context.quiet {
+        let names: Vec<&str> = prepared.iter().map(|p| p.step.name.as_str()).collect();
+        println!("-> Running {} step(s), up to {} at a time: {}", prepared.len(), jobs, names.join(", "));
+    }
+
+    let mut statuses: Vec<Option<(StepStatus, Option<Duration>)>> = prepared.iter().map(|_| None).collect();
+    let mut failure: Option<Box<dyn Error>> = None;
+    let signals_before = signal_generation();
+    std::thread::scope(|scope| {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut waiting: Vec<usize> = (0..prepared.len()).collect();
+        let mut running = 0;
+        loop {
+            if failure.is_none() && signal_generation() == signals_before {
+                let mut position = 0;
+                while running < jobs && position < waiting.len() {
+                    let slot = waiting[position];
+                    let ready = prepared[slot].step.needs.iter().all(|need| {
+                        prepared.iter().zip(&statuses)
+                            .any(|(p, status)| p.step.name == *need && matches!(status, Some((StepStatus::Passed, _))))
+                    });
+                    if !ready {
+                        position += 1;
+                        continue;
+                    }
+                    waiting.remove(position);
+                    running += 1;
+                    let step = &prepared[slot];
+                    let sender = sender.clone();
+                    scope.spawn(move || {
+                        let started = Instant::now();
+                        let result = context.launch(&step.argv, &step.environment, LaunchRole::Step(&step.step.name), false)
+                            .map_err(|e| e.to_string());
+                        let _ = sender.send((slot, result, started.elapsed()));
+                    });
+                }
+            }
+            if running == 0 {
+                break;
+            }
+            let Ok((slot, result, elapsed)) = receiver.recv() else { break };
+            running -= 1;
+            let step = &prepared[slot];
+            let role = LaunchRole::Step(&step.step.name);
+            let status = match result {
+                Ok(outcome) => {
+                    context.show_output(&outcome, role);
+                    match context.check_outcome(&outcome, role).and_then(|()| context.collect(&step.output_patterns)) {
+                        Ok(()) => StepStatus::Passed,
+                        Err(e) => {
+                            failure.get_or_insert(e);
+                            if outcome.timed_out { StepStatus::TimedOut } else { StepStatus::Failed(outcome.exit_code) }
+                        }
+                    }
+                }
+                Err(message) => {
+                    failure.get_or_insert(message.into());
+                    StepStatus::NotStarted
+                }
+            };
+            statuses[slot] = Some((status, Some(elapsed)));
+        }
+    });
+
+    let incomplete = statuses.iter().any(Option::is_none);
+    if !context.quiet {
+        let width = prepared.iter().map(|p| p.step.name.len()).max().unwrap_or(0);
+        println!("-> Step summary:");
+        for (step, status) in prepared.iter().zip(statuses.iter_mut()) {
+            let (status, elapsed) = status.take().unwrap_or((StepStatus::Skipped, None));
+            let status = match status {
+                StepStatus::Passed => "ok".to_string(),
+                StepStatus::Failed(code) => format!("failed (exit {})", code),
+                StepStatus::TimedOut => "timed out".to_string(),
+                StepStatus::NotStarted => "could not start".to_string(),
+                StepStatus::Skipped => "skipped".to_string(),
+            };
+            let elapsed = elapsed.map(|elapsed| format!("{:.1?}", elapsed)).unwrap_or_default();
+            let line = format!("   {:<width$}  {:<16} {}", step.step.name, status, elapsed, width = width);
+            println!("{}", line.trim_end());
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None if incomplete => Err(Box::new(ExitCodeError {
+            code: 128 + PENDING_SIGNAL.load(std::sync::atomic::Ordering::Relaxed),
+            message: "Pipeline was interrupted before all of its steps ran.".to_string(),
+        })),
+        None => Ok(()),
+    }
+}
+
+/// Creates the directory mounted at /sphere. Returns its path and, for a temporary workspace,
+/// the guard that removes it; `--keep-sandbox` and `--workdir` workspaces are left in place.
+fn create_workspace(options: &RunOptions, file_path: &Path) -> Result<(PathBuf, Option<tempfile::TempDir>), Box<dyn Error>> {
+    match &options.workdir {
+        Some(dir) => {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Workdir '{}' cannot be created: {}", dir.display(), e))?;
+            if fs::read_dir(dir)?.next().is_some() {
+                if !options.workdir_reuse {
+                    return Err(format!("Workdir '{}' is not empty; pass '--workdir-reuse' to delete its contents, or choose another directory.", dir.display()).into());
+                }
+                if fs::canonicalize(file_path)?.starts_with(fs::canonicalize(dir)?) {
+                    return Err(format!("Workdir '{}' holds '{}'; --workdir-reuse would delete it, so choose another directory.", dir.display(), file_path.display()).into());
+                }
+                clear_directory(dir)?;
+            }
+            Ok((fs::canonicalize(dir)?, None))
+        }
+        None => {
+            let temp_dir = tempfile::Builder::new().disable_cleanup(options.keep_sandbox).tempdir()?;
+            Ok((temp_dir.path().to_path_buf(), Some(temp_dir)))
+        }
+    }
+}
+
+// --- Main Application Logic for 'sphere run' ---
+fn run_sphere(file_path: &Path, options: &RunOptions, quiet: bool) -> Result<(), Box<dyn Error>> {
+    let started = std::time::SystemTime::now();
+    let mut report = RunReport {
+        manifest: file_path.display().to_string(),
+        sandbox_kept: options.keep_sandbox || options.workdir.is_some(),
+        ..RunReport::default()
+    };
+    let result = execute_run(file_path, options, quiet, &mut report);
+    if !options.captures_output() {
+        return result;
+    }
+    report.finish(started, &result);
+    let json = serde_json::to_string_pretty(&report)?;
+    if let Some(path) = &options.report {
+        let written = fs::write(path, format!("{}\n", json))
+            .map_err(|e| format!("Failed to write run report '{}': {}", path.display(), e));
+        match written {
+            Ok(()) if !quiet => println!("-> Wrote run report to '{}'.", path.display()),
+            Ok(()) => {}
+            Err(e) if result.is_ok() => return Err(e.into()),
+            Err(e) => eprintln!("-> {}", e),
+        }
+    }
+    if options.format == ReportFormat::Json {
+        println!("{}", json);
+    }
+    result
+}
+
+/// Runs a Sphere, filling in `report` as it goes.
+fn execute_run(file_path: &Path, options: &RunOptions, quiet: bool, report: &mut RunReport) -> Result<(), Box<dyn Error>> {
+    let content = fs::read_to_string(file_path)
+        .map_err(|e| format!("Failed to read sphere file '{}': {}", file_path.display(), e))?;
+    report.manifest_sha256 = Some(format!("{:x}", Sha256::digest(content.as_bytes())));
+    let sphere_process: SphereProcess = toml::from_str(&content)
+        .map_err(|e| format!("Failed to parse TOML from '{}': {}", file_path.display(), e))?;
+    
+    report.sphere_id = sphere_process.id.clone();
+    sphere_process.check_runnable(file_path)?;
+    if sphere_process.entrypoint.is_some() && options.step.is_some() {
+        return Err(format!("The file '{}' has no [[steps]]; '--step' only applies to pipelines.", file_path.display()).into());
+    }
+    if !quiet {
+        match (&sphere_process.entrypoint, &sphere_process.steps) {
+            (Some(entrypoint), _) => println!("-> Parsed entrypoint: '{}' from '{}'", entrypoint, file_path.display()),
+            (None, steps) => println!("-> Parsed {} step(s) from '{}'", steps.as_ref().map_or(0, Vec::len), file_path.display()),
+        }
+        if let Some(id) = &sphere_process.id {
+            println!("   Sphere ID: {}", id);
+        }
+    }
+
+    let mut resolved_deps: Vec<Dependency> = Vec::new();
+    let mut direct_deps: Vec<(String, usize)> = Vec::new();
+    if let Some(deps) = &sphere_process.dependencies {
+        if !quiet {
+            println!("-> Resolving dependencies...");
+        }
+        let lock_path = file_path.parent().unwrap_or(Path::new(".")).join(LOCKFILE_NAME);
+        let manifest_name = file_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let mut lock = LockFile::load(&lock_path)?.unwrap_or(LockFile { version: 1, ..LockFile::default() });
+        let mut resolver = DependencyResolver::new(options.offline, quiet)?;
+        if let Some(locked) = lock.spheres.get(&manifest_name) {
+            resolver.pin_locked(locked);
+        }
+        let root = sphere_process.id.clone().unwrap_or_else(|| file_path.display().to_string());
+        direct_deps = resolver.resolve_graph(deps, &root)?;
+        resolved_deps = resolver.resolved;
+        report.dependencies = dependency_reports(&direct_deps, &resolved_deps);
+
+        let mut entries: Vec<LockedDependency> = resolved_deps.iter()
+            .map(|dep| LockedDependency { id: dep.id.clone(), source: dep.source.clone(), sha256: dep.sha256.clone() })
+            .collect();
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        if verify_lock(lock.spheres.get(&manifest_name), &entries, &lock_path, options.locked)? {
+            lock.spheres.insert(manifest_name, entries);
+            lock.save(&lock_path)?;
+            if !quiet {
+                println!("-> Wrote resolved dependencies to '{}'.", lock_path.display());
+            }
+        } else if !quiet {
+            println!("-> Dependencies match '{}'.", lock_path.display());
+        }
+    } else if options.locked && !quiet {
+        println!("-> --locked has no effect: this Sphere declares no dependencies.");
+    }
+
+    let mut sandbox_config = SandboxConfig {
+        network: sphere_process.network,
+        ..SandboxConfig::default()
+    };
+    let manifest_dir = file_path.parent().unwrap_or(Path::new("."));
+    if let Some(permissions) = &sphere_process.permissions {
+        sandbox_config.grants = resolve_permissions(permissions, manifest_dir)?;
+        if !quiet {
+            for grant in &sandbox_config.grants {
+                println!("   - Granting {} access to '{}'", if grant.writable { "read-write" } else { "read-only" }, grant.path.display());
+            }
+        }
+    }
+    // Spheres that do not pick a profile get the default one wherever filtering is supported.
+    let seccomp_setting = sphere_process.seccomp.as_deref()
+        .or(if SECCOMP_SUPPORTED { Some("default") } else { None });
+    let seccomp_profile = match seccomp_setting {
+        Some(setting) => Some(load_seccomp_profile(setting, manifest_dir)?),
+        None => None,
+    };
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    if let (Some(setting), Some(profile)) = (seccomp_setting, &seccomp_profile) {
+        let unknown = unknown_syscalls(profile);
+        if !unknown.is_empty() {
+            eprintln!("-> WARNING: Seccomp profile '{}' names syscalls this host's architecture does not have; their rules are skipped: {}", setting, unknown.join(", "));
+        }
+    }
+
+    let output_patterns = compile_output_patterns(sphere_process.outputs.as_deref().unwrap_or_default())?;
+    let mut environment = sphere_environment(&sphere_process, &options.env)?;
+    let passed_through: Vec<String> = host_passthrough(&sphere_process)?.into_keys().collect();
+    if let Some(id) = &sphere_process.id {
+        environment.entry("SPHERE_ID".to_string()).or_insert_with(|| id.clone());
+    }
+
+    let timeout = options.timeout.or(sphere_process.timeout);
+    let attempts = sphere_process.retries.unwrap_or(0).saturating_add(1);
+    let signals_before = signal_generation();
+    let mut history = Vec::new();
+    let result = loop {
+        let attempt = history.len() as u32 + 1;
+        if attempts > 1 && !quiet {
+            println!("-> Attempt {} of {}", attempt, attempts);
+        }
+        let started = Instant::now();
+        let mut record = AttemptReport {
+            attempt,
+            started_at: format_timestamp(std::time::SystemTime::now()),
+            ..AttemptReport::default()
+        };
+        let result = (|| -> Result<(), Box<dyn Error>> {
+            if attempt > 1 && let Some(workdir) = &options.workdir {
+                clear_directory(workdir)?;
+            }
+            let (workspace, _workspace_guard) = create_workspace(options, file_path)?;
+            record.sandbox_path = Some(workspace.display().to_string());
+            if options.workdir.is_some() || options.keep_sandbox {
+                eprintln!("-> Sandbox workspace will be kept at: {}", workspace.display());
+            } else if !quiet {
+                println!("-> Created secure sandbox at: {:?}", workspace);
+            }
+            let bin_path = workspace.join("bin");
+            fs::create_dir(&bin_path)?;
+
+            write_shims(&bin_path, &direct_deps, &resolved_deps, &passed_through)?;
+
+            fs::create_dir(workspace.join(SPHERE_HOME_DIR))?;
+            let staged_inputs = stage_inputs(sphere_process.inputs.as_deref().unwrap_or_default(), manifest_dir, &sandbox_config.grants, &workspace)?;
+            if !quiet && !staged_inputs.is_empty() {
+                println!("-> Staged inputs:");
+                for input in &staged_inputs {
+                    println!("   {} -> {} ({} file(s), sha256:{})", input.from, input.to, input.files, input.sha256);
+                }
+            }
+            record.inputs = staged_inputs;
+            let mut environment = environment.clone();
+            if attempts > 1 {
+                environment.insert("SPHERE_ATTEMPT".to_string(), attempt.to_string());
+            }
+
+            // Interpreter scripts live outside the workspace, so outputs never pick them up.
+            let scripts_dir = tempdir()?;
+            let sandbox = if options.no_sandbox { None } else { Some(Sandbox::create(&workspace, scripts_dir.path(), &sandbox_config)?) };
+            let context = RunContext {
+                options,
+                quiet,
+                workspace,
+                bin_path,
+                scripts_dir: scripts_dir.path().to_path_buf(),
+                sandbox_config: &sandbox_config,
+                sandbox,
+                limits: sphere_process.limits.clone().unwrap_or_default(),
+                limits_declared: sphere_process.limits.is_some(),
+                seccomp_setting,
+                seccomp_profile: seccomp_profile.as_ref(),
+                timeout,
+                deadline: timeout.map(|timeout| Instant::now() + timeout),
+                success_codes: sphere_process.success_codes.clone().unwrap_or_else(|| vec![0]),
+                announced: std::sync::atomic::AtomicBool::new(false),
+                processes: std::sync::Mutex::new(Vec::new()),
+                limit_cgroups: std::sync::Mutex::new(Vec::new()),
+            };
+            let result = run_lifecycle(&context, &sphere_process, environment, &output_patterns);
+            record.processes = context.processes.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+            result
+        })();
+        let elapsed = started.elapsed();
+        record.ended_at = format_timestamp(std::time::SystemTime::now());
+        record.exit_code = result_exit_code(&result);
+        record.error = result.as_ref().err().map(|e| e.to_string());
+        report.attempts.push(record);
+        let retry = match &result {
+            Err(e) if attempt < attempts && signal_generation() == signals_before => is_retryable(e.as_ref(), sphere_process.retry_on.as_deref()),
+            _ => false,
+        };
+        history.push((result.as_ref().err().map(|e| e.to_string()), elapsed));
+        if !retry {
+            break result;
+        }
+        let delay = retry_delay(&sphere_process, attempt);
+        if !quiet && delay.is_zero() {
+            println!("-> Attempt {} failed; retrying...", attempt);
+        } else if !quiet {
+            println!("-> Attempt {} failed; retrying in {:?}...", attempt, delay);
+        }
+        if !sleep_unless_signalled(delay, signals_before) {
+            break result;
+        }
+    };
+
+    if history.len() > 1 && !quiet {
+        println!("-> Attempts:");
+        for (index, (error, elapsed)) in history.iter().enumerate() {
+            let outcome = error.as_deref().unwrap_or("ok");
+            println!("   {}  {:<8.1?}  {}", index + 1, elapsed, outcome);
+        }
+    }
+    result
+}
+
+/// Runs one attempt inside a prepared workspace: `setup`, then the entrypoint or pipeline and
+/// output collection, then `on_failure` if that did not succeed, and always `teardown`.
+fn run_lifecycle(context: &RunContext, process: &SphereProcess, mut environment: BTreeMap<String, String>, output_patterns: &[glob::Pattern]) -> Result<(), Box<dyn Error>> {
+    let mut result = match &process.setup {
+        Some(setup) => context.run_hook(process, "setup", setup, &environment),
+        None => Ok(()),
+    };
+    if result.is_ok() {
+        result = match &process.entrypoint {
+            Some(entrypoint) => context.run_entrypoint(process, entrypoint, &environment),
+            None => run_pipeline(context, process, &environment),
+        };
+    }
+    if result.is_ok() {
+        result = context.collect(output_patterns);
+    }
+
+    // Hooks that run after the work can tell how it went from SPHERE_EXIT_CODE.
+    let exit_code = result_exit_code(&result);
+    environment.insert("SPHERE_EXIT_CODE".to_string(), exit_code.to_string());
+    if result.is_err() && let Some(on_failure) = &process.on_failure
+        && let Err(e) = context.run_hook(process, "on_failure", on_failure, &environment) {
+        eprintln!("-> The on_failure hook failed as well: {}", e);
+    }
+    if let Some(teardown) = &process.teardown {
+        let teardown_result = context.run_hook(process, "teardown", teardown, &environment);
+        match (&result, teardown_result) {
+            (Ok(()), teardown_result) => result = teardown_result,
+            (Err(_), Err(e)) => eprintln!("-> The teardown hook failed as well: {}", e),
+            (Err(_), Ok(())) => {}
+        }
+    }
+    result
+}
+
+/// Prints buffered output in "--- Command STDOUT ---" / "--- Command STDERR ---" sections.
+fn print_framed_output(output: &Output, quiet: bool) {
+    if !quiet { 
+        println!("--- Command STDOUT ---");
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !stdout.is_empty() {
+        println!("{}", stdout);
+    } else if !quiet { 
+        println!("(empty)");
+    }
+    if !quiet {
+        println!("----------------------");
+    }
+
+    if !output.stderr.is_empty() {
+        if !quiet {
+            println!("\n--- Command STDERR ---");
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        println!("{}", stderr); 
+        if !quiet {
+            println!("----------------------");
+        }
+    }
+}
+
+// --- Run Reports ---
+/// The machine-readable record of a `sphere run`, written by `--report` and `--format json`.
+#[derive(Serialize, Default)]
+struct RunReport {
+    sphere_id: Option<String>,
+    manifest: String,
+    manifest_sha256: Option<String>,
+    dependencies: Vec<DependencyReport>,
+    /// The inputs the last attempt staged, with their digests.
+    inputs: Vec<StagedInput>,
+    /// The last attempt's workspace; it only still exists with `--keep-sandbox` or `--workdir`.
+    sandbox_path: Option<String>,
+    sandbox_kept: bool,
+    started_at: String,
+    ended_at: String,
+    success: bool,
+    /// The status `sphere run` exits with.
+    exit_code: i32,
+    signal: Option<i32>,
+    timed_out: bool,
+    error: Option<String>,
+    /// How many syscalls the seccomp filter stopped or reported, over every attempt.
+    seccomp_violations: usize,
+    /// The output of the entrypoint, or of the step or hook that failed.
+    stdout: Option<String>,
+    stderr: Option<String>,
+    attempts: Vec<AttemptReport>,
+}
+
+#[derive(Serialize)]
+struct DependencyReport {
+    alias: String,
+    id: String,
+    source: String,
+    sha256: String,
+}
+
+#[derive(Serialize, Default)]
+struct AttemptReport {
+    attempt: u32,
+    sandbox_path: Option<String>,
+    started_at: String,
+    ended_at: String,
+    exit_code: i32,
+    error: Option<String>,
+    inputs: Vec<StagedInput>,
+    processes: Vec<ProcessReport>,
+}
+
+/// One launched process: the entrypoint, a step or a hook.
+#[derive(Serialize)]
+struct ProcessReport {
+    role: &'static str,
+    name: Option<String>,
+    started_at: String,
+    ended_at: String,
+    exit_code: i32,
+    /// Only set when the process was killed by a signal; an exit code above 128 alone is not one.
+    signal: Option<i32>,
+    timed_out: bool,
+    seccomp_violations: Vec<SeccompViolationReport>,
+    stdout: Option<String>,
+    stderr: Option<String>,
+}
+
+/// How often the seccomp filter stopped ("blocked") or reported ("logged") one syscall.
+#[derive(Serialize)]
+struct SeccompViolationReport {
+    syscall: String,
+    action: &'static str,
+    count: usize,
+}
+
+impl RunReport {
+    /// Fills in the overall outcome once the run is over.
+    fn finish(&mut self, started: std::time::SystemTime, result: &Result<(), Box<dyn Error>>) {
+        self.started_at = format_timestamp(started);
+        self.ended_at = format_timestamp(std::time::SystemTime::now());
+        self.success = result.is_ok();
+        self.exit_code = result_exit_code(result);
+        self.error = result.as_ref().err().map(|e| e.to_string());
+        self.seccomp_violations = self.attempts.iter()
+            .flat_map(|attempt| &attempt.processes)
+            .flat_map(|process| &process.seccomp_violations)
+            .map(|violation| violation.count)
+            .sum();
+        let Some(last) = self.attempts.last() else {
+            return;
+        };
+        self.sandbox_path = last.sandbox_path.clone();
+        self.inputs = last.inputs.clone();
+        let primary = last.processes.iter().find(|process| process.role == "entrypoint")
+            .or_else(|| last.processes.iter().find(|process| process.timed_out || (self.exit_code != 0 && process.exit_code == self.exit_code)));
+        if let Some(process) = primary {
+            self.signal = process.signal;
+            self.timed_out = process.timed_out;
+            self.stdout = process.stdout.clone();
+            self.stderr = process.stderr.clone();
+        }
+    }
+}
+
+/// One entry per resolved dependency, under the first alias that reaches it.
+fn dependency_reports(direct: &[(String, usize)], graph: &[Dependency]) -> Vec<DependencyReport> {
+    let mut aliases: Vec<Option<&str>> = vec![None; graph.len()];
+    for (alias, index) in direct.iter().chain(graph.iter().flat_map(|dependency| &dependency.dependencies)) {
+        aliases[*index].get_or_insert(alias);
+    }
+    graph.iter().zip(aliases)
+        .map(|(dependency, alias)| DependencyReport {
+            alias: alias.unwrap_or_default().to_string(),
+            id: dependency.id.clone(),
+            source: dependency.source.clone(),
+            sha256: dependency.sha256.clone(),
+        })
+        .collect()
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with milliseconds, e.g. "2024-05-01T12:00:00.250Z".
+fn format_timestamp(time: std::time::SystemTime) -> String {
+    let since_epoch = time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's `civil_from_days`.
+    let days = seconds / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, seconds % 86_400 / 3_600, seconds % 3_600 / 60, seconds % 60, since_epoch.subsec_millis(),
+    )
+}
+
+// --- Retries ---
+/// Whether a failed attempt is worth repeating: only processes that ran and failed are, and with
+/// `retry_on` only when their exit code is listed.
+fn is_retryable(error: &(dyn Error + 'static), retry_on: Option<&[i32]>) -> bool {
+    match error.downcast_ref::<ExitCodeError>() {
+        Some(exit) => retry_on.is_none_or(|codes| codes.contains(&exit.code)),
+        None => false,
+    }
+}
+
+/// The wait after failed attempt number `attempt` (counting from 1).
+fn retry_delay(process: &SphereProcess, attempt: u32) -> Duration {
+    let delay = process.retry_delay.unwrap_or_default();
+    match process.retry_backoff {
+        RetryBackoff::Fixed => delay,
+        RetryBackoff::Exponential => delay.saturating_mul(2u32.saturating_pow(attempt - 1)),
+    }
+}
+
+/// Sleeps for `delay`, returning false early if a signal arrives, since our handler keeps
+/// SIGINT from ending `sphere` itself.
+fn sleep_unless_signalled(delay: Duration, signals_before: usize) -> bool {
+    let deadline = Instant::now() + delay;
+    while Instant::now() < deadline {
+        if signal_generation() != signals_before {
+            return false;
+        }
+        std::thread::sleep(WAIT_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+    }
+    signal_generation() == signals_before
+}
+
+/// Empties a `--workdir` between attempts, and before the first with `--workdir-reuse`, so each
+/// one starts from a clean workspace.
+fn clear_directory(dir: &Path) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// --- Watch Mode ---
+/// The files a watched Sphere depends on: its manifest, its declared inputs, and dependencies
+/// registered in the cache index by absolute path. Dependencies are resolved offline, since
+/// hub downloads never change once cached.
+fn watch_targets(file_path: &Path, quiet: bool) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let manifest = fs::canonicalize(file_path)
+        .map_err(|e| format!("Failed to watch '{}': {}", file_path.display(), e))?;
+    let mut targets = vec![manifest.clone()];
+    let manifest_dir = manifest.parent().unwrap_or(Path::new("/"));
+    let Ok(process) = fs::read_to_string(&manifest).map_err(Box::<dyn Error>::from)
+        .and_then(|content| toml::from_str::<SphereProcess>(&content).map_err(Box::<dyn Error>::from)) else {
+        // The run reports what is wrong with the manifest; until it is fixed only it is watched.
+        return Ok(targets);
+    };
+    for input in process.inputs.iter().flatten() {
+        if let Ok(source) = fs::canonicalize(manifest_dir.join(&input.from)) {
+            targets.push(source);
+        }
+    }
+    if let Some(deps) = &process.dependencies {
+        let mut resolver = DependencyResolver::new(true, true)?;
+        let manifest_name = manifest.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if let Some(lock) = LockFile::load(&manifest_dir.join(LOCKFILE_NAME))?
+            && let Some(locked) = lock.spheres.get(&manifest_name) {
+            resolver.pin_locked(locked);
+        }
+        let root = process.id.clone().unwrap_or_else(|| file_path.display().to_string());
+        match resolver.resolve_graph(deps, &root) {
+            Ok(_) => {
+                for dependency in &resolver.resolved {
+                    if is_registered_in_place(&dependency.source) {
+                        targets.push(PathBuf::from(&dependency.source["cache:".len()..]));
+                    }
+                }
+            }
+            Err(e) if !quiet => println!("-> Not watching dependencies, they could not be resolved from the cache: {}", e),
+            Err(_) => {}
+        }
+    }
+    targets.sort();
+    targets.dedup();
+    Ok(targets)
+}
+
+/// Watches `targets`: directories recursively, and files through their parent directory, so
+/// editors that save by replacing the file are still noticed.
+fn watch_paths(watcher: &mut impl Watcher, targets: &[PathBuf]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut watched = Vec::new();
+    for target in targets {
+        let (path, mode) = if target.is_dir() {
+            (target.as_path(), notify::RecursiveMode::Recursive)
+        } else {
+            (target.parent().unwrap_or(Path::new("/")), notify::RecursiveMode::NonRecursive)
+        };
+        if watched.iter().any(|w: &PathBuf| w == path) {
+            continue;
+        }
+        watcher.watch(path, mode)
+            .map_err(|e| format!("Failed to watch '{}': {}", path.display(), e))?;
+        watched.push(path.to_path_buf());
+    }
+    Ok(watched)
+}
+
+/// Starts `sphere run` for a watched file or a service as a child in its own process group, so
+/// that only we decide when it is stopped. It never gets the terminal's stdin.
+fn spawn_run_process(file_path: &Path, run_args: &[String], quiet: bool) -> Result<Child, Box<dyn Error>> {
+    let mut command = Command::new(std::env::current_exe()?);
+    if quiet {
+        command.arg("--quiet");
+    }
+    command.arg("run").arg(file_path);
+    if !run_args.iter().take_while(|arg| *arg != "--").any(|arg| arg == "--no-stdin") {
+        command.arg("--no-stdin");
+    }
+    command.args(run_args).stdin(Stdio::null());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    Ok(command.spawn()?)
+}
+
+/// Stops a run started by `spawn_run_process` with `signal` and waits for it, giving its
+/// `on_failure` and `teardown` hooks the chance to run.
+fn stop_run_process(child: &mut Child, signal: i32) -> io::Result<ExitStatus> {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, signal);
+    }
+    #[cfg(not(unix))]
+    let _ = signal;
+    child.wait()
+}
+
+fn handle_watch(file_path: &Path, debounce: Duration, run_args: &[String], quiet: bool) -> Result<(), Box<dyn Error>> {
+    #[cfg(unix)]
+    let sigterm = libc::SIGTERM;
+    #[cfg(not(unix))]
+    let sigterm = 15;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    }).map_err(|e| format!("Failed to watch '{}': {}", file_path.display(), e))?;
+    install_signal_forwarding();
+    let signals_before = signal_generation();
+
+    let mut targets = Vec::new();
+    let mut watched: Vec<PathBuf> = Vec::new();
+    let mut run: Option<Child> = None;
+    // Set when a change arrives; the next run starts once nothing has changed for `debounce`.
+    let mut last_change = Some(Instant::now().checked_sub(debounce).unwrap_or_else(Instant::now));
+    loop {
+        if signal_generation() != signals_before {
+            if let Some(mut child) = run.take() {
+                stop_run_process(&mut child, PENDING_SIGNAL.load(std::sync::atomic::Ordering::Relaxed))?;
+            }
+            if !quiet {
+                println!("-> Stopped watching '{}'.", file_path.display());
+            }
+            return Ok(());
+        }
+        if let Some(changed) = last_change && changed.elapsed() >= debounce {
+            last_change = None;
+            if let Some(mut child) = run.take() {
+                if !quiet {
+                    println!("-> Change detected; stopping the current run...");
+                }
+                stop_run_process(&mut child, sigterm)?;
+            }
+            // The manifest may now declare different inputs or dependencies.
+            let new_targets = watch_targets(file_path, quiet)?;
+            if new_targets != targets {
+                for path in &watched {
+                    let _ = watcher.unwatch(path);
+                }
+                watched = watch_paths(&mut watcher, &new_targets)?;
+                targets = new_targets;
+                if !quiet {
+                    println!("-> Watching {} path(s) for changes (Ctrl-C to stop):", targets.len());
+                    for target in &targets {
+                        println!("   {}", target.display());
+                    }
+                }
+            }
+            run = Some(spawn_run_process(file_path, run_args, quiet)?);
+        }
+        if let Some(child) = run.as_mut() && let Some(status) = child.try_wait()? {
+            run = None;
+            if !quiet {
+                match exit_code_of(&status) {
+                    0 => println!("-> Run succeeded; waiting for changes..."),
+                    code => println!("-> Run exited with status {}; waiting for changes...", code),
+                }
+            }
+        }
+        match receiver.recv_timeout(WAIT_POLL_INTERVAL) {
+            Ok(Ok(event)) => {
+                let relevant = !matches!(event.kind, notify::EventKind::Access(_))
+                    && event.paths.iter().any(|path| targets.iter().any(|target| path.starts_with(target)));
+                if relevant {
+                    last_change = Some(Instant::now());
+                }
+            }
+            Ok(Err(e)) => eprintln!("-> File watching reported an error: {}", e),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return Err("Failed to watch: the file watcher stopped unexpectedly.".into()),
+        }
+    }
+}
+
+// --- Services ---
+/// Pause before a service is started again, so one that fails at once does not spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// How long `sphere down` waits for a service's hooks and sandbox to shut down.
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ServiceStatus {
+    Starting,
+    Running,
+    Restarting,
+    Exited,
+    Stopped,
+}
+
+/// A service's state file, `~/.sphere/services/<name>.json`, written by `sphere up` and then
+/// kept up to date by the service's supervisor. Its output goes to `<name>.log` next to it.
+#[derive(Serialize, Deserialize, Debug)]
+struct ServiceState {
+    name: String,
+    manifest: PathBuf,
+    run_args: Vec<String>,
+    restart: RestartPolicy,
+    /// The supervisor's pid. The service runs in a `sphere run` child of it.
+    pid: Option<u32>,
+    /// When the supervisor started, in clock ticks since boot, so a reused pid is not mistaken for it.
+    #[serde(default)]
+    pid_started: Option<u64>,
+    /// The process group of the current `sphere run` child, led by that child, and when it started.
+    /// Lets `sphere down` stop the service even after the supervisor has died.
+    #[serde(default)]
+    run_pgid: Option<u32>,
+    #[serde(default)]
+    run_started: Option<u64>,
+    status: ServiceStatus,
+    restarts: u32,
+    last_exit_code: Option<i32>,
+    /// Seconds since the Unix epoch.
+    started_at: u64,
+}
+
+impl ServiceState {
+    fn path(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+        Ok(get_services_dir()?.join(format!("{}.json", name)))
+    }
+
+    fn load(name: &str) -> Result<Option<ServiceState>, Box<dyn Error>> {
+        let path = ServiceState::path(name)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)?;
+        let state = serde_json::from_str(&content)
+            .map_err(|e| format!("Service state file '{}' is corrupt: {}", path.display(), e))?;
+        Ok(Some(state))
+    }
+
+    /// Writes the state through a temporary file, so `sphere ps` never reads half of it.
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = ServiceState::path(&self.name)?;
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    /// The supervisor's pid, if it is still alive.
+    fn live_pid(&self) -> Option<u32> {
+        self.pid.filter(|pid| process_matches(*pid, self.pid_started, &["__supervise", &self.name]))
+    }
+
+    /// The process group of the `sphere run` child, if its leader is still alive.
+    fn live_run_pgid(&self) -> Option<u32> {
+        let manifest = self.manifest.to_string_lossy();
+        self.run_pgid.filter(|pgid| process_matches(*pgid, self.run_started, &["run", &manifest]))
+    }
+}
+
+fn get_services_dir() -> Result<PathBuf, Box<dyn Error>> {
+    let (cache_dir, _index_path) = get_cache_paths()?;
+    let services_dir = cache_dir.parent().unwrap_or(&cache_dir).join("services");
+    fs::create_dir_all(&services_dir)?;
+    Ok(services_dir)
+}
+
+fn validate_service_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+        return Err(format!("Service name '{}' is invalid: use letters, digits, '.', '_' and '-', not starting with '.'.", name).into());
+    }
+    Ok(())
+}
+
+fn process_alive(pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        // A supervisor that died but was never reaped still answers kill(0).
+        if let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid))
+            && stat.rsplit_once(')').is_some_and(|(_, fields)| fields.trim_start().starts_with('Z')) {
+            return false;
+        }
+        let alive = unsafe { libc::kill(pid as libc::pid_t, 0) == 0 };
+        alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pid;
+        false
+    }
+}
+
+/// When `pid` started, in clock ticks since boot (field 22 of `/proc/<pid>/stat`).
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields after the parenthesised command name start with field 3, the state.
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+/// Whether `pid` is alive and still the process that was recorded: it started at `started` and
+/// its command line includes every one of `args`. Without `/proc` only liveness can be checked.
+fn process_matches(pid: u32, started: Option<u64>, args: &[&str]) -> bool {
+    if !process_alive(pid) {
+        return false;
+    }
+    if cfg!(not(target_os = "linux")) {
+        return true;
+    }
+    let Ok(cmdline) = fs::read(format!("/proc/{}/cmdline", pid)) else { return false };
+    let cmdline: Vec<&[u8]> = cmdline.split(|byte| *byte == 0).collect();
+    started.is_some()
+        && process_start_time(pid) == started
+        && args.iter().all(|arg| cmdline.contains(&arg.as_bytes()))
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn format_uptime(seconds: u64) -> String {
+    match seconds {
+        0..60 => format!("{}s", seconds),
+        60..3600 => format!("{}m{}s", seconds / 60, seconds % 60),
+        3600..86400 => format!("{}h{}m", seconds / 3600, seconds % 3600 / 60),
+        _ => format!("{}d{}h", seconds / 86400, seconds % 86400 / 3600),
+    }
+}
+
+fn handle_up(file_path: &Path, name: Option<&str>, run_args: &[String], quiet: bool) -> Result<(), Box<dyn Error>> {
+    let content = fs::read_to_string(file_path)
+        .map_err(|e| format!("Failed to read sphere file '{}': {}", file_path.display(), e))?;
+    let sphere_process: SphereProcess = toml::from_str(&content)
+        .map_err(|e| format!("Failed to parse TOML from '{}': {}", file_path.display(), e))?;
+    sphere_process.check_runnable(file_path)?;
+    if sphere_process.kind != SphereKind::Service {
+        return Err(format!("The file '{}' is not a service; add kind = \"service\" to start it with 'sphere up', or use 'sphere run'.", file_path.display()).into());
+    }
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => file_path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+    };
+    validate_service_name(&name)?;
+    if let Some(state) = ServiceState::load(&name)? && let Some(pid) = state.live_pid() {
+        return Err(format!("Service '{}' is already running (supervisor pid {}); stop it with 'sphere down {}'.", name, pid, name).into());
+    }
+    // Only the host's network namespace is reachable from outside the sandbox. Arguments after
+    // `--` go to the entrypoint, not to `sphere run`.
+    if sphere_process.network != NetworkPolicy::Host && !run_args.iter().take_while(|arg| *arg != "--").any(|arg| arg == "--no-sandbox") {
+        eprintln!("-> WARNING: Service '{}' uses network = \"{}\", so it gets a network namespace of its own and nothing outside its sandbox can connect to it. Set network = \"host\" if it serves other processes.", name, sphere_process.network.as_str());
+    }
+
+    let log_path = get_services_dir()?.join(format!("{}.log", name));
+    let log = fs::File::create(&log_path)?;
+    let state = ServiceState {
+        name,
+        manifest: fs::canonicalize(file_path)?,
+        run_args: run_args.to_vec(),
+        restart: sphere_process.restart,
+        pid: None,
+        pid_started: None,
+        run_pgid: None,
+        run_started: None,
+        status: ServiceStatus::Starting,
+        restarts: 0,
+        last_exit_code: None,
+        started_at: unix_time(),
+    };
+    state.save()?;
+
+    // The supervisor gets a session of its own, so it outlives this terminal.
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("__supervise").arg(&state.name)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        unsafe {
+            command.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+    }
+    let supervisor = command.spawn()?;
+    if !quiet {
+        println!("-> Started service '{}' (supervisor pid {}, restart = \"{}\").", state.name, supervisor.id(), match state.restart {
+            RestartPolicy::No => "no",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+        });
+        println!("   Logs: {}", log_path.display());
+    }
+    Ok(())
+}
+
+/// The body of a service's supervisor: runs the service with `sphere run`, records each exit, and
+/// starts it again as its restart policy says until `sphere down` sends SIGTERM.
+fn handle_supervise(name: &str) -> Result<(), Box<dyn Error>> {
+    let mut state = ServiceState::load(name)?
+        .ok_or_else(|| format!("Service '{}' has no state file.", name))?;
+    install_signal_forwarding();
+    let signals_before = signal_generation();
+    state.pid = Some(std::process::id());
+    state.pid_started = process_start_time(std::process::id());
+    loop {
+        println!("-> Starting service '{}' from '{}'...", state.name, state.manifest.display());
+        let mut child = spawn_run_process(&state.manifest, &state.run_args, false)?;
+        // The child leads a process group of its own, so its pid is also the group's id.
+        state.run_pgid = Some(child.id());
+        state.run_started = process_start_time(child.id());
+        state.status = ServiceStatus::Running;
+        state.save()?;
+        let (status, stopped) = loop {
+            if let Some(status) = child.try_wait()? {
+                break (status, false);
+            }
+            if signal_generation() != signals_before {
+                break (stop_run_process(&mut child, PENDING_SIGNAL.load(std::sync::atomic::Ordering::Relaxed))?, true);
+            }
+            std::thread::sleep(WAIT_POLL_INTERVAL);
+        };
+        let exit_code = exit_code_of(&status);
+        state.last_exit_code = Some(exit_code);
+        state.run_pgid = None;
+        state.run_started = None;
+        if stopped {
+            state.status = ServiceStatus::Stopped;
+            state.save()?;
+            println!("-> Service '{}' stopped.", state.name);
+            return Ok(());
+        }
+        let restart = match state.restart {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure => exit_code != 0,
+            RestartPolicy::Always => true,
+        };
+        if !restart {
+            state.status = ServiceStatus::Exited;
+            state.save()?;
+            println!("-> Service '{}' exited with status {}.", state.name, exit_code);
+            return Ok(());
+        }
+        state.status = ServiceStatus::Restarting;
+        state.restarts += 1;
+        state.save()?;
+        println!("-> Service '{}' exited with status {}; restarting in {:?}...", state.name, exit_code, RESTART_DELAY);
+        if !sleep_unless_signalled(RESTART_DELAY, signals_before) {
+            state.status = ServiceStatus::Stopped;
+            state.save()?;
+            println!("-> Service '{}' stopped.", state.name);
+            return Ok(());
+        }
+    }
+}
+
+fn handle_down(name: &str, quiet: bool) -> Result<(), Box<dyn Error>> {
+    validate_service_name(name)?;
+    let state = ServiceState::load(name)?
+        .ok_or_else(|| format!("Service '{}' is not known; 'sphere ps' lists the services.", name))?;
+    match (state.live_pid(), state.live_run_pgid()) {
+        (Some(pid), _) => {
+            if !quiet {
+                println!("-> Stopping service '{}' (supervisor pid {})...", name, pid);
+            }
+            #[cfg(unix)]
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGTERM);
+            }
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while state.live_pid().is_some() {
+                if Instant::now() >= deadline {
+                    return Err(format!("Service '{}' did not stop within {:?}; its supervisor (pid {}) is still running.", name, STOP_TIMEOUT, pid).into());
+                }
+                std::thread::sleep(WAIT_POLL_INTERVAL);
+            }
+        }
+        (None, Some(pgid)) => {
+            if !quiet {
+                println!("-> Service '{}' lost its supervisor; stopping its run (process group {})...", name, pgid);
+            }
+            #[cfg(unix)]
+            unsafe {
+                libc::killpg(pgid as libc::pid_t, libc::SIGTERM);
+            }
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while state.live_run_pgid().is_some() {
+                if Instant::now() >= deadline {
+                    return Err(format!("Service '{}' did not stop within {:?}; its run (process group {}) is still running.", name, STOP_TIMEOUT, pgid).into());
+                }
+                std::thread::sleep(WAIT_POLL_INTERVAL);
+            }
+        }
+        (None, None) if !quiet => println!("-> Service '{}' was not running.", name),
+        (None, None) => {}
+    }
+    fs::remove_file(ServiceState::path(name)?)?;
+    if !quiet {
+        println!("-> Service '{}' is down; its log stays at '{}' until it is started again.", name, get_services_dir()?.join(format!("{}.log", name)).display());
+    }
+    Ok(())
+}
+
+fn handle_ps(quiet: bool) -> Result<(), Box<dyn Error>> {
+    let services_dir = get_services_dir()?;
+    let mut names: Vec<String> = fs::read_dir(&services_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".json").map(str::to_string))
+        .collect();
+    names.sort();
+    if names.is_empty() {
+        if !quiet {
+            println!("-> No services. Start one with 'sphere up <file>'.");
+        }
+        return Ok(());
+    }
+    println!("   {:<20} {:<14} {:<8} {:<9} {:<8} MANIFEST", "NAME", "STATUS", "PID", "RESTARTS", "UPTIME");
+    for name in names {
+        let Some(state) = ServiceState::load(&name)? else { continue };
+        let live_pid = state.live_pid();
+        let status = match (state.status, live_pid) {
+            (ServiceStatus::Exited, _) => format!("exited ({})", state.last_exit_code.unwrap_or_default()),
+            (ServiceStatus::Stopped, _) => "stopped".to_string(),
+            (_, None) => "dead".to_string(),
+            (ServiceStatus::Starting, _) => "starting".to_string(),
+            (ServiceStatus::Running, _) => "running".to_string(),
+            (ServiceStatus::Restarting, _) => "restarting".to_string(),
+        };
+        let pid = live_pid.map_or("-".to_string(), |pid| pid.to_string());
+        let uptime = if live_pid.is_some() { format_uptime(unix_time().saturating_sub(state.started_at)) } else { "-".to_string() };
+        println!("   {:<20} {:<14} {:<8} {:<9} {:<8} {}", state.name, status, pid, state.restarts, uptime, state.manifest.display());
+    }
+    Ok(())
+}
+
+fn handle_logs(name: &str, follow: bool) -> Result<(), Box<dyn Error>> {
+    validate_service_name(name)?;
+    let log_path = get_services_dir()?.join(format!("{}.log", name));
+    let mut log = fs::File::open(&log_path)
+        .map_err(|e| format!("Service '{}' has no log at '{}': {}", name, log_path.display(), e))?;
+    io::copy(&mut log, &mut io::stdout().lock())?;
+    if !follow {
+        return Ok(());
+    }
+    install_signal_forwarding();
+    let signals_before = signal_generation();
+    // Follow until Ctrl-C, or until the supervisor is gone and everything it wrote is shown.
+    while signal_generation() == signals_before {
+        let running = ServiceState::load(name)?.is_some_and(|state| state.live_pid().is_some());
+        std::thread::sleep(WAIT_POLL_INTERVAL * 10);
+        io::copy(&mut log, &mut io::stdout().lock())?;
+        if !running {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// --- Main function: Parses CLI args and dispatches to handlers ---
+fn main() {
+    let cli = Cli::parse();
+
+    let result = match &cli.command { 
+        Commands::Run { file_path, no_sandbox, timeout, output_mode, no_stdin, env, out_dir, keep_sandbox, workdir, workdir_reuse, locked, offline, jobs, step, report, format, args } => {
+            let options = RunOptions {
+                no_sandbox: *no_sandbox,
+                timeout: *timeout,
+                output_mode: *output_mode,
+                no_stdin: *no_stdin,
+                env: env.clone(),
+                out_dir: out_dir.clone(),
+                keep_sandbox: *keep_sandbox,
+                workdir: workdir.clone(),
+                workdir_reuse: *workdir_reuse,
+                locked: *locked,
+                offline: *offline || std::env::var("SPHERE_OFFLINE").is_ok_and(|value| value == "1" || value == "true"),
+                jobs: *jobs,
+                step: step.clone(),
+                report: report.clone(),
+                format: *format,
+                args: args.clone(),
+            };
+            // The JSON report must be all that reaches stdout.
+            run_sphere(file_path, &options, cli.quiet || *format == ReportFormat::Json)
+        }
+        Commands::Watch { debounce, file_path, run_args } => {
+            handle_watch(file_path, *debounce, run_args, cli.quiet)
+        }
+        Commands::Up { name, file_path, run_args } => {
+            handle_up(file_path, name.as_deref(), run_args, cli.quiet)
+        }
+        Commands::Down { name } => {
+            handle_down(name, cli.quiet)
+        }
+        Commands::Ps => {
+            handle_ps(cli.quiet)
+        }
+        Commands::Logs { name, follow } => {
+            handle_logs(name, *follow)
+        }
+        Commands::Supervise { name } => {
+            handle_supervise(name)
+        }
+        Commands::Cache { action } => match action { 
+            CacheAction::List => {
+                handle_cache_list(cli.quiet)
+            }
+            CacheAction::Add { id, sphere_file_path, copy_to_cache } => {
+                handle_cache_add(id, sphere_file_path, *copy_to_cache, cli.quiet)
+            }
+            CacheAction::Remove { id } => {
+                handle_cache_remove(id, cli.quiet)
+            }
+        },
+        Commands::Publish { file_path } => { 
+            handle_sphere_publish(file_path, cli.quiet)
+        }
+    };
+
+    if let Err(e) = result {
+        let mut error_message = format!("{}", e);
+        let mut specific_error_handled = false;
+        let mut file_path_for_error: Option<String> = None;
+
+        match &cli.command {
+            Commands::Run { file_path, .. } | Commands::Watch { file_path, .. } | Commands::Up { file_path, .. } => {
+                file_path_for_error = Some(file_path.display().to_string());
+            }
+            Commands::Publish { file_path } => {
+                 file_path_for_error = Some(file_path.display().to_string());
+            }
+            Commands::Cache { action } => {
+                if let CacheAction::Add { sphere_file_path, .. } = action {
+                    file_path_for_error = Some(sphere_file_path.display().to_string());
+                }
+            }
+            Commands::Down { .. } | Commands::Ps | Commands::Logs { .. } | Commands::Supervise { .. } => {}
+        }
+
+        if let Some(toml_error) = e.downcast_ref::<toml::de::Error>() {
+            let path_str = file_path_for_error.as_deref().unwrap_or("the specified .sphere file");
+            if toml_error.message().contains("missing field `entrypoint`") {
+                error_message = format!("The file '{}' is missing the required 'entrypoint' field.", path_str);
+                specific_error_handled = true;
+            } else { 
+                 error_message = format!("Failed to parse TOML from '{}'. Reason: {}", path_str, toml_error);
+                 specific_error_handled = true;
+            }
+        }
+        
+        if !specific_error_handled {
+            let custom_prefixes = [
+                "Dependency", "Failed to read sphere file", "Failed to parse TOML from",
+                "Sphere ID", "Source file", "A file named", "Failed to copy",
+                "Failed to get absolute path", "Failed to save cache index",
+                "Failed to parse cache index", "Could not determine home directory",
+                "Cannot derive a valid cache filename", "Failed to fetch SphereHub master index",
+                /* "Sphere ID" is too generic, use more specific part of the error message */
+                "not found in the public SphereHub registry", "Failed to fetch Sphere file",
+                "Hash mismatch for Sphere", "Failed to save downloaded Sphere",
+                "Failed to start ", "Failed to create the sandbox", "Failed to join the sandbox", "Sandboxing requires Linux", "Permission path", "Limit '", "Sphere timed out", "seccomp profile", "Seccomp",
+                "Sphere exited with status", "Environment variable name", "Output pattern", "Input '", "Input target", "Workdir '",
+                "The 'entrypoint' array", "Lockfile '", "Offline mode",
+                "The file '", "Step '", "Pipeline", "Hook '", "Failed to watch", "Service", "Failed to write run report"
+            ];
+            if !custom_prefixes.iter().any(|p| e.to_string().contains(p)) { // Changed to .contains() for broader matching
+                error_message = format!("Application error: {}", e);
+            }
+        }
+        
+        eprintln!("\nError: {}", error_message.trim());
+        std::process::exit(e.downcast_ref::<ExitCodeError>().map_or(1, |exit| exit.code));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_accepts_units_and_bare_seconds() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("12"), Ok(Duration::from_secs(12)));
+        assert_eq!(parse_duration(" 2 s "), Ok(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn parse_duration_rejects_bad_input() {
+        assert!(parse_duration("").unwrap_err().contains("expected a number"));
+        assert!(parse_duration("s").unwrap_err().contains("expected a number"));
+        assert!(parse_duration("10d").unwrap_err().contains("unknown unit 'd'"));
+        assert!(parse_duration("1.2.3s").is_err());
+    }
+
+    /// A resolver whose cache index lists `ids` and whose SphereHub index is empty, so versions are
+    /// chosen without the network.
+    fn resolver_with(ids: &[&str]) -> DependencyResolver {
+        DependencyResolver {
+            cache_dir: PathBuf::from("/nonexistent"),
+            index_path: PathBuf::from("/nonexistent/index.json"),
+            local_index: ids.iter().map(|id| (id.to_string(), format!("{}.sphere", id.replace('/', "_")))).collect(),
+            http_client: None,
+            offline: false,
+            quiet: true,
+            resolved: Vec::new(),
+            missing: Vec::new(),
+            hub_index: Some(HashMap::new()),
+            requirements: HashMap::new(),
+            selected: HashMap::new(),
+            pinned: HashMap::new(),
+        }
+    }
+
+    fn versioned(id: &str, version: &str) -> DependencySpec {
+        DependencySpec::Versioned { id: id.to_string(), version: version.to_string() }
+    }
+
+    const GREETER_VERSIONS: &[&str] = &["com.util.greeter/v1.0.0", "com.util.greeter/v1.2.0", "com.util.greeter/v1.4.1", "com.util.greeter/v2.0.0"];
+
+    #[test]
+    fn select_picks_the_highest_matching_version() {
+        let mut resolver = resolver_with(GREETER_VERSIONS);
+        let chain = ["root".to_string()];
+        let selected = resolver.select("greet", &versioned("com.util.greeter", "^1.2"), &chain).unwrap();
+        assert_eq!(selected.as_deref(), Some("com.util.greeter/v1.4.1"));
+        // A later requirement the selected version meets reuses it.
+        let again = resolver.select("hello", &versioned("com.util.greeter", ">=1.1"), &chain).unwrap();
+        assert_eq!(again.as_deref(), Some("com.util.greeter/v1.4.1"));
+    }
+
+    #[test]
+    fn select_keeps_exact_and_unversioned_ids() {
+        let mut resolver = resolver_with(GREETER_VERSIONS);
+        let chain = ["root".to_string()];
+        let exact = DependencySpec::Exact("com.util.greeter/v1.0.0".to_string());
+        assert_eq!(resolver.select("greet", &exact, &chain).unwrap().as_deref(), Some("com.util.greeter/v1.0.0"));
+        let unversioned = DependencySpec::Exact("com.util.formatter".to_string());
+        assert_eq!(resolver.select("fmt", &unversioned, &chain).unwrap().as_deref(), Some("com.util.formatter"));
+    }
+
+    #[test]
+    fn select_explains_a_requirement_no_version_meets() {
+        let mut resolver = resolver_with(GREETER_VERSIONS);
+        let error = resolver.select("greet", &versioned("com.util.greeter", "^3"), &["root".to_string()]).unwrap_err().to_string();
+        assert_eq!(error, "Dependency 'greet' requires 'com.util.greeter' ^3, but no available version matches.\n   Available versions: 1.0.0, 1.2.0, 1.4.1, 2.0.0");
+    }
+
+    #[test]
+    fn select_lists_every_clashing_requirement() {
+        let mut resolver = resolver_with(GREETER_VERSIONS);
+        resolver.select("greet", &versioned("com.util.greeter", "^1"), &["root".to_string()]).unwrap();
+        let chain = ["root".to_string(), "com.util.banner/v1.0.0".to_string()];
+        let error = resolver.select("greeter", &versioned("com.util.greeter", "^2.1"), &chain).unwrap_err().to_string();
+        assert_eq!(error, concat!(
+            "Dependency version conflict on 'com.util.greeter': no version satisfies every requirement:\n",
+            "   - ^1 (alias 'greet' in root)\n",
+            "   - ^2.1 (alias 'greeter' in root -> com.util.banner/v1.0.0)\n",
+            "   Available versions: 1.0.0, 1.2.0, 1.4.1, 2.0.0",
+        ));
+    }
+
+    #[test]
+    fn select_pins_a_version_that_settles_a_conflict() {
+        let mut resolver = resolver_with(GREETER_VERSIONS);
+        let chain = ["root".to_string()];
+        resolver.select("greet", &versioned("com.util.greeter", "^1"), &chain).unwrap();
+        let error = resolver.select("hello", &versioned("com.util.greeter", "~1.2"), &chain).unwrap_err();
+        assert!(error.is::<ResolutionRestart>());
+        assert_eq!(resolver.pinned["com.util.greeter"], semver::Version::new(1, 2, 0));
+
+        // The next pass starts from the pinned version.
+        resolver.requirements.clear();
+        resolver.selected.clear();
+        let selected = resolver.select("greet", &versioned("com.util.greeter", "^1"), &chain).unwrap();
+        assert_eq!(selected.as_deref(), Some("com.util.greeter/v1.2.0"));
+    }
+
+    #[test]
+    fn offline_select_collects_every_unavailable_dependency() {
+        let mut resolver = resolver_with(&["com.util.b/v2.0.0"]);
+        resolver.offline = true;
+        let chain = ["root".to_string()];
+        assert_eq!(resolver.select("a", &versioned("com.util.a", "^1"), &chain).unwrap(), None);
+        assert_eq!(resolver.select("b", &versioned("com.util.b", "^1"), &chain).unwrap(), None);
+        assert_eq!(resolver.select("c", &versioned("com.util.c", "*"), &chain).unwrap(), None);
+        assert_eq!(resolver.missing, [
+            "'com.util.a' ^1 (alias 'a' in root): no version is cached",
+            "'com.util.b' ^1 (alias 'b' in root): no cached version matches (cached: 2.0.0)",
+            "'com.util.c' * (alias 'c' in root): no version is cached",
+        ]);
+    }
+
+    fn locked(id: &str, source: &str) -> LockedDependency {
+        LockedDependency { id: id.to_string(), source: source.to_string(), sha256: "ab".repeat(32) }
+    }
+
+    #[test]
+    fn verify_lock_treats_a_new_source_as_drift() {
+        let lock_path = Path::new("sphere.lock");
+        let lock = vec![locked("com.util.greeter/v1.0.0", "cache:/home/me/greeter.sphere")];
+        let moved = [locked("com.util.greeter/v1.0.0", "hub:greeter-1.0.0.sphere")];
+        assert!(verify_lock(Some(&lock), &moved, lock_path, false).unwrap());
+        let error = verify_lock(Some(&lock), &moved, lock_path, true).unwrap_err().to_string();
+        assert!(error.contains("now comes from hub:greeter-1.0.0.sphere instead of cache:/home/me/greeter.sphere"), "{}", error);
+
+        // A SphereHub download read back from the cache is the same source.
+        let downloaded = vec![locked("com.util.greeter/v1.0.0", "hub:greeter-1.0.0.sphere")];
+        let cached = [locked("com.util.greeter/v1.0.0", "cache:greeter-1.0.0.sphere")];
+        assert!(!verify_lock(Some(&downloaded), &cached, lock_path, true).unwrap());
+    }
+
+    fn step(name: &str, needs: &[&str]) -> SphereStep {
+        SphereStep {
+            name: name.to_string(),
+            run: Entrypoint::Script("true".to_string()),
+            needs: needs.iter().map(|need| need.to_string()).collect(),
+            env: None,
+            outputs: None,
+        }
+    }
+
+    fn step_names(steps: &[SphereStep], order: &[usize]) -> Vec<String> {
+        order.iter().map(|&index| steps[index].name.clone()).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn describe_limit_hit_needs_a_real_signal() {
+        use std::os::unix::process::ExitStatusExt;
+        let limits = SphereLimits { cpu_seconds: Some(1), max_file_size_mb: Some(1), ..SphereLimits::default() };
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+        let killed = |signal: i32| ExitStatus::from_raw(signal);
+        let used = Duration::from_secs(2);
+        assert_eq!(describe_limit_hit(&limits, &exited(128 + libc::SIGXCPU), used, None), None);
+        assert_eq!(describe_limit_hit(&limits, &exited(137), used, None), None);
+        assert_eq!(describe_limit_hit(&limits, &exited(128 + libc::SIGXFSZ), used, None), None);
+        assert!(describe_limit_hit(&limits, &killed(libc::SIGXCPU), used, None).is_some_and(|hit| hit.starts_with("cpu_seconds")));
+        assert!(describe_limit_hit(&limits, &killed(libc::SIGKILL), used, None).is_some_and(|hit| hit.starts_with("cpu_seconds")));
+        assert_eq!(describe_limit_hit(&limits, &killed(libc::SIGKILL), Duration::ZERO, None), None);
+        assert!(describe_limit_hit(&limits, &killed(libc::SIGXFSZ), Duration::ZERO, None).is_some_and(|hit| hit.starts_with("max_file_size_mb")));
+    }
+
+    #[test]
+    fn stage_inputs_keeps_the_shims_directory() {
+        let project = tempdir().unwrap();
+        fs::create_dir_all(project.path().join("tools/bin")).unwrap();
+        fs::write(project.path().join("tools/bin/tool"), "tool").unwrap();
+        let input = |from: &str, to: &str| SphereInput { from: from.to_string(), to: to.to_string() };
+        for (from, to) in [("tools/bin/tool", "bin/"), ("tools/bin", "./bin/extra"), ("tools", "."), ("tools", "")] {
+            let workspace = tempdir().unwrap();
+            let Err(error) = stage_inputs(&[input(from, to)], project.path(), &[], workspace.path()) else {
+                panic!("{} -> {} was staged", from, to);
+            };
+            assert!(error.to_string().contains("would overwrite the dependency shims"), "{} -> {}: {}", from, to, error);
+        }
+        let workspace = tempdir().unwrap();
+        let staged = stage_inputs(&[input("tools/bin", "tools")], project.path(), &[], workspace.path()).unwrap();
+        assert_eq!(staged[0].files, 1);
+        assert!(workspace.path().join("tools/tool").exists());
+    }
+
+    #[test]
+    fn plan_steps_orders_needs_first() {
+        let steps = [step("package", &["test", "build"]), step("test", &["build"]), step("build", &[]), step("lint", &[])];
+        let order = plan_steps(&steps, None).unwrap();
+        assert_eq!(step_names(&steps, &order), ["build", "test", "package", "lint"]);
+    }
+
+    #[test]
+    fn plan_steps_with_a_target_runs_only_what_it_needs() {
+        let steps = [step("build", &[]), step("lint", &[]), step("test", &["build"]), step("package", &["test"])];
+        let order = plan_steps(&steps, Some("test")).unwrap();
+        assert_eq!(step_names(&steps, &order), ["build", "test"]);
+        let error = plan_steps(&steps, Some("deploy")).unwrap_err().to_string();
+        assert_eq!(error, "Step 'deploy' does not exist; the steps are: build, lint, test, package.");
+    }
+
+    #[test]
+    fn plan_steps_rejects_cycles_duplicates_and_unknown_needs() {
+        let cycle = [step("a", &["c"]), step("b", &["a"]), step("c", &["b"])];
+        assert_eq!(plan_steps(&cycle, None).unwrap_err().to_string(), "Pipeline steps form a cycle: a -> c -> b -> a");
+        let own_need = [step("a", &["a"])];
+        assert_eq!(plan_steps(&own_need, None).unwrap_err().to_string(), "Pipeline steps form a cycle: a -> a");
+        let duplicate = [step("a", &[]), step("b", &[]), step("a", &["b"])];
+        assert_eq!(plan_steps(&duplicate, None).unwrap_err().to_string(), "Step 'a' is defined more than once.");
+        let unknown = [step("a", &["setup"])];
+        assert_eq!(plan_steps(&unknown, None).unwrap_err().to_string(), "Step 'a' needs 'setup', which is not a step of this Sphere.");
+    }
+
+    /// Runs a seccomp BPF program the way the kernel would, for the instructions `program` emits.
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn run_bpf(program: &[libc::sock_filter], arch: u32, nr: i32, args: [u64; 6]) -> u32 {
+        use libc::{BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+        let data = libc::seccomp_data { nr, arch, instruction_pointer: 0, args };
+        let bytes = unsafe {
+            std::slice::from_raw_parts((&data as *const libc::seccomp_data).cast::<u8>(), std::mem::size_of::<libc::seccomp_data>())
+        };
+        let (mut accumulator, mut pc) = (0u32, 0usize);
+        loop {
+            let instruction = program.get(pc).expect("jumped past the end of the program");
+            pc += 1;
+            match instruction.code as u32 {
+                code if code == BPF_LD | BPF_W | BPF_ABS => {
+                    let offset = instruction.k as usize;
+                    accumulator = u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap());
+                }
+                code if code == BPF_ALU | BPF_AND | BPF_K => accumulator &= instruction.k,
+                code if code == BPF_JMP | BPF_JEQ | BPF_K || code == BPF_JMP | BPF_JGE | BPF_K => {
+                    let taken = if code == BPF_JMP | BPF_JEQ | BPF_K { accumulator == instruction.k } else { accumulator >= instruction.k };
+                    pc += if taken { instruction.jt } else { instruction.jf } as usize;
+                }
+                code if code == BPF_RET | BPF_K => return instruction.k,
+                code => panic!("unexpected BPF instruction {:#x}", code),
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn filter_from(profile: &str) -> SeccompFilter {
+        SeccompFilter::compile(&toml::from_str(profile).unwrap()).unwrap()
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn seccomp_program_applies_eq_ne_and_mask_conditions() {
+        let filter = filter_from(r#"
+            default_action = "allow"
+
+            [[syscalls]]
+            names = ["socket"]
+            action = "kill"
+            args = [{ index = 0, value = 10 }, { index = 1, mask = 15, op = "ne", value = 1 }]
+
+            [[syscalls]]
+            names = ["socket"]
+            action = "errno"
+            args = [{ index = 2, op = "ne", value = 0 }]
+
+            [[syscalls]]
+            names = ["ptrace"]
+            action = "enosys"
+        "#);
+        let program = filter.program(false);
+        let arch = seccomp_arch::AUDIT_ARCH;
+        let socket = libc::SYS_socket as i32;
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+        let eperm = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        // The first rule needs both conditions; the mask drops SOCK_CLOEXEC and friends.
+        assert_eq!(run_bpf(&program, arch, socket, [10, 2, 0, 0, 0, 0]), kill);
+        assert_eq!(run_bpf(&program, arch, socket, [10, 2 | 0x80000, 0, 0, 0, 0]), kill);
+        assert_eq!(run_bpf(&program, arch, socket, [10, 1 | 0x80000, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        // Failing the first rule falls through to the second one.
+        assert_eq!(run_bpf(&program, arch, socket, [2, 1, 6, 0, 0, 0]), eperm);
+        assert_eq!(run_bpf(&program, arch, socket, [2, 1, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        // Only the low 32 bits of an argument are compared.
+        assert_eq!(run_bpf(&program, arch, socket, [10 | 1 << 32, 2, 0, 0, 0, 0]), kill);
+        assert_eq!(run_bpf(&program, arch, libc::SYS_ptrace as i32, [0; 6]), libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+        assert_eq!(run_bpf(&program, arch, libc::SYS_getpid as i32, [0; 6]), libc::SECCOMP_RET_ALLOW);
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn seccomp_profiles_skip_syscalls_the_architecture_lacks() {
+        let profile: SeccompProfile = toml::from_str(r#"
+            default_action = "errno"
+
+            [[syscalls]]
+            names = ["mkdirat", "_llseek", "openat", "_llseek", "arm_fadvise64_64"]
+            action = "allow"
+        "#).unwrap();
+        assert_eq!(unknown_syscalls(&profile), ["_llseek", "arm_fadvise64_64"]);
+        let program = SeccompFilter::compile(&profile).unwrap().program(false);
+        let arch = seccomp_arch::AUDIT_ARCH;
+        assert_eq!(run_bpf(&program, arch, libc::SYS_mkdirat as i32, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run_bpf(&program, arch, libc::SYS_getpid as i32, [0; 6]), libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        assert_eq!(syscall_number("io_uring_setup"), Some(libc::SYS_io_uring_setup));
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(syscall_number("mkdir"), Some(libc::SYS_mkdir));
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn seccomp_program_agrees_with_action_for() {
+        let strict = load_seccomp_profile("strict", Path::new(".")).unwrap();
+        let filter = SeccompFilter::compile(&strict).unwrap();
+        let interesting_args: [u64; 8] = [0, 1, 2, 3, 10, 17, 0x1000_0000, 0x7E02_0000];
+        for notify in [false, true] {
+            let program = filter.program(notify);
+            for (_, number) in seccomp_arch::SYSCALLS.iter().chain(seccomp_arch::ARCH_SYSCALLS) {
+                for first in interesting_args {
+                    for second in interesting_args {
+                        let args = [first, second, 0, 0, 0, 0];
+                        let expected = match filter.action_for(*number, &args) {
+                            SeccompAction::Allow => libc::SECCOMP_RET_ALLOW,
+                            SeccompAction::Log | SeccompAction::Errno if notify => libc::SECCOMP_RET_USER_NOTIF,
+                            SeccompAction::Log => libc::SECCOMP_RET_ALLOW,
+                            SeccompAction::Errno => libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+                            SeccompAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+                            SeccompAction::Enosys => libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+                        };
+                        assert_eq!(run_bpf(&program, seccomp_arch::AUDIT_ARCH, *number as i32, args), expected, "syscall {}", number);
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn seccomp_strict_blocks_namespace_creation() {
+        let filter = SeccompFilter::compile(&load_seccomp_profile("strict", Path::new(".")).unwrap()).unwrap();
+        let program = filter.program(false);
+        let arch = seccomp_arch::AUDIT_ARCH;
+        let eperm = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        assert_eq!(run_bpf(&program, arch, libc::SYS_clone as i32, [libc::CLONE_NEWUSER as u64, 0, 0, 0, 0, 0]), eperm);
+        assert_eq!(run_bpf(&program, arch, libc::SYS_clone as i32, [libc::SIGCHLD as u64, 0, 0, 0, 0, 0]), libc::SECCOMP_RET_ALLOW);
+        assert_eq!(run_bpf(&program, arch, libc::SYS_clone3 as i32, [0; 6]), libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+        assert_eq!(run_bpf(&program, arch, libc::SYS_unshare as i32, [0; 6]), eperm);
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[test]
+    fn seccomp_program_rejects_foreign_architectures() {
+        let program = filter_from("default_action = \"allow\"").program(false);
+        let kill = libc::SECCOMP_RET_KILL_PROCESS;
+        assert_eq!(run_bpf(&program, 0x4000_0003, libc::SYS_getpid as i32, [0; 6]), kill);
+        #[cfg(target_arch = "x86_64")]
+        {
+            // x32 syscalls carry the 0x40000000 bit and the x86_64 audit arch.
+            let x32_bit = 0x4000_0000;
+            assert_eq!(run_bpf(&program, seccomp_arch::AUDIT_ARCH, x32_bit | libc::SYS_getpid as i32, [0; 6]), kill);
+            assert_eq!(run_bpf(&program, seccomp_arch::AUDIT_ARCH, libc::SYS_getpid as i32, [0; 6]), libc::SECCOMP_RET_ALLOW);
+        }
+    }
+
+    #[test]
+    fn format_timestamp_writes_utc_with_milliseconds() {
+        let at = |seconds: u64, millis: u64| format_timestamp(std::time::UNIX_EPOCH + Duration::from_millis(seconds * 1000 + millis));
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(946_684_799, 999), "1999-12-31T23:59:59.999Z");
+        assert_eq!(at(951_868_800, 0), "2000-03-01T00:00:00.000Z");
+        assert_eq!(at(1_709_210_096, 789), "2024-02-29T12:34:56.789Z");
+        assert_eq!(at(1_709_210_096, 7), "2024-02-29T12:34:56.007Z");
     }
 }